@@ -0,0 +1,66 @@
+use super::common::*;
+
+use std::path::Path;
+
+use super::role_users;
+use super::role_www;
+use super::role_pkgsrc;
+use super::role_sshd;
+
+/*
+ * Each role that has configuration or files worth validating provides a check
+ * function, which returns a list of the problems it found.
+ */
+type CheckFn = fn(&Path) -> Result<Vec<String>>;
+
+const CHECKS: &[(&str, CheckFn)] = &[
+    ("users", role_users::check),
+    ("www", role_www::check),
+    ("pkgsrc", role_pkgsrc::check),
+    ("sshd", role_sshd::check),
+];
+
+/*
+ * "confomat check [-d DIR]": validate the configuration and files in the tree
+ * without making any changes to the local system, so that mistakes are found
+ * before we deploy to a host.
+ */
+pub fn run(args: &[String]) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("d", "", "directory containing config/ and files/", "DIR");
+
+    let p = match opts.parse(args) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            eprintln!("{}", opts.short_usage("confomat check"));
+            std::process::exit(2);
+        }
+    };
+
+    let root = tree_root(p.opt_str("d"))?;
+
+    let mut problems: Vec<String> = Vec::new();
+    for (role, check) in CHECKS {
+        match check(&root) {
+            Ok(mut list) => {
+                list.sort();
+                for l in list {
+                    problems.push(format!("{}: {}", role, l));
+                }
+            }
+            Err(e) => problems.push(format!("{}: {}", role, e)),
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{}: ok", root.display());
+        return Ok(());
+    }
+
+    for p in &problems {
+        eprintln!("ERROR: {}", p);
+    }
+    eprintln!("{} problem(s) found in {}", problems.len(), root.display());
+    std::process::exit(1);
+}
@@ -0,0 +1,104 @@
+use super::common::*;
+
+use std::path::PathBuf;
+
+use super::role_users::{self, ExportFormat, NewUser};
+
+pub const SUBCOMMANDS: &[&str] = &["add", "export"];
+
+fn usage(opts: &getopts::Options, cmd: &str, e: &str) -> ! {
+    eprintln!("ERROR: {}", e);
+    eprintln!("{}", opts.short_usage(&format!("confomat users {}", cmd)));
+    std::process::exit(2);
+}
+
+/*
+ * "confomat users export": print the effective users and groups from
+ * users.toml in a form that other systems can consume, so that every system
+ * agrees on the same identities.
+ */
+fn export(args: &[String]) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("d", "", "directory containing config/ and files/", "DIR");
+    opts.optopt("f", "format", "output format (passwd, group, ldif, json)",
+        "FORMAT");
+    opts.optopt("H", "host", "only include users with an account on HOST",
+        "HOST");
+    opts.optopt("b", "base-dn", "base DN for LDIF output", "DN");
+
+    let p = match opts.parse(args) {
+        Ok(p) => p,
+        Err(e) => usage(&opts, "export", &e.to_string()),
+    };
+
+    let format = match p.opt_str("f").as_deref() {
+        Some("passwd") => ExportFormat::Passwd,
+        Some("group") => ExportFormat::Group,
+        Some("ldif") => match p.opt_str("b") {
+            Some(base) => ExportFormat::Ldif(base),
+            None => usage(&opts, "export", "must specify a base DN with -b \
+                for ldif"),
+        },
+        Some("json") => ExportFormat::Json,
+        Some(f) => usage(&opts, "export", &format!("unknown format {:?}", f)),
+        None => usage(&opts, "export", "must specify a format with -f"),
+    };
+
+    let root = tree_root(p.opt_str("d"))?;
+
+    print!("{}", role_users::export(&root, &format,
+        p.opt_str("H").as_deref())?);
+
+    Ok(())
+}
+
+/*
+ * "confomat users add": add a user to users.toml with the next free uid for
+ * their class, and install their public key in the files directory.
+ */
+fn add(args: &[String]) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("d", "", "directory containing config/ and files/", "DIR");
+    opts.optopt("c", "class", "user class (ops, general, service)", "CLASS");
+    opts.optopt("C", "gecos", "full name or comment for the user", "GECOS");
+    opts.optopt("g", "group", "primary group, if not the class default",
+        "GROUP");
+    opts.optopt("k", "key", "file containing SSH public keys", "KEYFILE");
+
+    let p = match opts.parse(args) {
+        Ok(p) => p,
+        Err(e) => usage(&opts, "add", &e.to_string()),
+    };
+
+    let name = match p.free.as_slice() {
+        [name] => name.to_string(),
+        _ => usage(&opts, "add", "must specify exactly one user name"),
+    };
+    let class = if let Some(class) = p.opt_str("c") {
+        class
+    } else {
+        usage(&opts, "add", "must specify a class with -c");
+    };
+
+    let root = tree_root(p.opt_str("d"))?;
+
+    let uid = role_users::add(&root, &NewUser {
+        name: name.to_string(),
+        class,
+        gecos: p.opt_str("C"),
+        group: p.opt_str("g"),
+        key: p.opt_str("k").map(PathBuf::from),
+    })?;
+
+    println!("added user {} with uid {}", name, uid);
+
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    match args[0].as_str() {
+        "add" => add(&args[1..]),
+        "export" => export(&args[1..]),
+        n => bail!("unknown users subcommand {:?}", n),
+    }
+}
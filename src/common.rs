@@ -3,6 +3,458 @@ pub use slog::{info, warn, error, debug, trace, o};
 pub use anyhow::{Result, anyhow, bail};
 pub use confomat::*;
 
+use std::path::{Path, PathBuf};
+
 pub fn sleep(s: u64) {
     std::thread::sleep(std::time::Duration::from_secs(s));
 }
+
+/*
+ * A policy for retrying an operation that may fail for a while before it
+ * succeeds.  The delay between attempts starts at "initial_delay" seconds and
+ * doubles after each failure, up to "max_delay".  We give up after "attempts"
+ * tries, or once the next attempt would begin more than "deadline" seconds
+ * after the first.
+ */
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Retry {
+    pub attempts: u32,
+    pub initial_delay: u64,
+    pub max_delay: u64,
+    pub deadline: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            attempts: 8,
+            initial_delay: 5,
+            max_delay: 300,
+            deadline: 1800,
+        }
+    }
+}
+
+/*
+ * Call "f" until it succeeds or the retry policy is exhausted, in which case
+ * the error from the last attempt is returned.
+ */
+pub fn retry<T, F>(log: &slog::Logger, what: &str, policy: &Retry, mut f: F)
+    -> Result<T>
+    where F: FnMut() -> Result<T>,
+{
+    let start = std::time::Instant::now();
+    let mut delay = policy.initial_delay;
+    let mut attempt = 1;
+
+    loop {
+        let e = match f() {
+            Ok(res) => return Ok(res),
+            Err(e) => e,
+        };
+
+        let elapsed = start.elapsed().as_secs();
+        if attempt >= policy.attempts || elapsed + delay > policy.deadline {
+            bail!("{} failed after {} attempt(s) over {} seconds: {}", what,
+                attempt, elapsed, e);
+        }
+
+        warn!(log, "{} failed (attempt {} of {}), retrying in {} seconds: {}",
+            what, attempt, policy.attempts, delay, e);
+        sleep(delay);
+
+        delay = std::cmp::min(delay.saturating_mul(2), policy.max_delay);
+        attempt += 1;
+    }
+}
+
+/*
+ * Run a command through the Context, with everything it prints collected in a
+ * file.  If the command fails, the error includes that output, so that the
+ * cause can be reported.
+ */
+pub fn run_capture(c: &Context, args: &[&str]) -> Result<()> {
+    let out = format!("/var/run/.confomat.{}.out", std::process::id());
+
+    let mut cmd = vec!["/bin/sh", "-c", "o=$1; shift; exec \"$@\" >\"$o\" 2>&1",
+        "sh", &out];
+    cmd.extend(args);
+    let res = c.run(&cmd);
+
+    let printed = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_file(&out).ok();
+    if let Err(e) = res {
+        bail!("{}: {}\n{}", args[0], e, printed.trim());
+    }
+
+    Ok(())
+}
+
+/*
+ * Determine the node name of the local system, which is used to select host
+ * specific configuration.
+ */
+pub fn nodename(c: &Context) -> Result<String> {
+    match c.read_lines("/etc/nodename")? {
+        Some(l) => match l.first() {
+            Some(n) if !n.trim().is_empty() => Ok(n.trim().to_string()),
+            _ => bail!("/etc/nodename is empty"),
+        },
+        None => bail!("/etc/nodename is missing"),
+    }
+}
+
+/*
+ * Determine whether a ZFS dataset exists.
+ */
+pub fn dataset_exists(name: &str) -> Result<bool> {
+    let out = std::process::Command::new("/sbin/zfs")
+        .env_clear()
+        .arg("list")
+        .arg("-H")
+        .arg("-o").arg("name")
+        .arg(name)
+        .output()?;
+
+    Ok(out.status.success())
+}
+
+/*
+ * List the ZFS file systems on this system that have a mountpoint, along
+ * with that mountpoint.
+ */
+pub fn mounted_datasets() -> Result<Vec<(String, String)>> {
+    let out = std::process::Command::new("/sbin/zfs")
+        .env_clear()
+        .arg("list")
+        .arg("-H")
+        .arg("-t").arg("filesystem")
+        .arg("-o").arg("name,mountpoint")
+        .output()?;
+
+    if !out.status.success() {
+        bail!("zfs list failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim());
+    }
+
+    Ok(String::from_utf8(out.stdout)?.lines().filter_map(|l| {
+        let t: Vec<&str> = l.split('\t').collect();
+        if t.len() == 2 && t[1].starts_with('/') {
+            Some((t[0].to_string(), t[1].to_string()))
+        } else {
+            None
+        }
+    }).collect())
+}
+
+/*
+ * Get the values of several ZFS dataset properties with a single command, in
+ * the exact (parseable) form.
+ */
+pub fn dataset_props(name: &str, props: &[&str])
+    -> Result<std::collections::BTreeMap<String, String>>
+{
+    if props.is_empty() {
+        return Ok(Default::default());
+    }
+
+    let out = std::process::Command::new("/sbin/zfs")
+        .env_clear()
+        .arg("get")
+        .arg("-Hp")
+        .arg("-o").arg("property,value")
+        .arg(props.join(","))
+        .arg(name)
+        .output()?;
+
+    if !out.status.success() {
+        bail!("zfs get {} {} failed: {}", props.join(","), name,
+            String::from_utf8_lossy(&out.stderr).trim());
+    }
+
+    Ok(String::from_utf8(out.stdout)?.lines().filter_map(|l| {
+        let mut t = l.splitn(2, '\t');
+        match (t.next(), t.next()) {
+            (Some(k), Some(v)) => Some((k.to_string(), v.to_string())),
+            _ => None,
+        }
+    }).collect())
+}
+
+/*
+ * Parse a ZFS size value such as "10G" into a number of bytes.  The value
+ * "none" is reported as zero by "zfs get -p".
+ */
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim().to_ascii_uppercase();
+    if s == "NONE" {
+        return Some(0);
+    }
+
+    let s = s.trim_end_matches('B');
+    let (n, mult) = match s.chars().last()? {
+        'K' => (&s[..s.len() - 1], 1u64 << 10),
+        'M' => (&s[..s.len() - 1], 1u64 << 20),
+        'G' => (&s[..s.len() - 1], 1u64 << 30),
+        'T' => (&s[..s.len() - 1], 1u64 << 40),
+        'P' => (&s[..s.len() - 1], 1u64 << 50),
+        _ => (s, 1),
+    };
+
+    n.parse::<f64>().ok().map(|n| (n * mult as f64) as u64)
+}
+
+/*
+ * Make sure each of the listed properties of an existing ZFS dataset has the
+ * value we expect, using "zfs set" only for those that differ.
+ */
+pub fn ensure_dataset_props(c: &Context, name: &str,
+    props: &std::collections::BTreeMap<String, String>) -> Result<()>
+{
+    let log = c.log();
+
+    let names: Vec<&str> = props.keys().map(|k| k.as_str()).collect();
+    let current = dataset_props(name, &names)?;
+
+    for (k, v) in props.iter() {
+        let cur = current.get(k).map(|s| s.as_str()).unwrap_or("");
+
+        let same = match k.as_str() {
+            "quota" | "refquota" | "reservation" | "refreservation"
+                | "recordsize" | "volblocksize" =>
+            {
+                parse_size(v).is_some() && parse_size(v) == parse_size(cur)
+            }
+            _ => cur == v.as_str(),
+        };
+
+        if !same {
+            info!(log, "dataset {}: {} {} -> {}", name, k, cur, v);
+            c.run(&["/sbin/zfs", "set", &format!("{}={}", k, v), name])?;
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Locate the root of the confomat tree; i.e., the directory that contains the
+ * "config" and "files" directories.  If no directory was specified, we look
+ * first in the current directory and then in the directories above the
+ * executable, which is installed as "bin/confomat" within the tree.
+ */
+pub fn tree_root(dir: Option<String>) -> Result<PathBuf> {
+    let is_root = |p: &Path| -> bool {
+        p.join("config").is_dir() && p.join("files").is_dir()
+    };
+
+    if let Some(dir) = dir {
+        let p = PathBuf::from(dir);
+        if !is_root(&p) {
+            bail!("{} does not contain config/ and files/", p.display());
+        }
+        return Ok(p);
+    }
+
+    let cwd = std::env::current_dir()?;
+    if is_root(&cwd) {
+        return Ok(cwd);
+    }
+
+    let exe = std::env::current_exe()?;
+    for p in exe.ancestors().skip(1) {
+        if is_root(p) {
+            return Ok(p.to_path_buf());
+        }
+    }
+
+    bail!("could not locate config/ and files/ directories; use -d DIR");
+}
+
+/*
+ * Locate the root of the tree from which the Context loads configuration and
+ * files, using a file that belongs to the current role; i.e., one found at
+ * "files/<role>/<name>" within the tree.  Roles that build on the
+ * configuration of another role use this, rather than guessing at the tree
+ * with tree_root().
+ *
+ * The Context only provides the configuration of the current role, so this
+ * depends on confomat finding role files at "files/<role>" next to the
+ * "config" directory; i.e., the same layout that tree_root() and the deploy
+ * scripts assume.  The role must ship the named file, and the check that
+ * "config" exists at the root we find will fail loudly should that layout
+ * ever change.
+ */
+pub fn context_root(c: &Context, name: &str) -> Result<PathBuf> {
+    let f = c.file(name)?;
+    let depth = Path::new(name).components().count() + 2;
+
+    match f.ancestors().nth(depth) {
+        Some(root) if root.join("config").is_dir() => Ok(root.to_path_buf()),
+        _ => bail!("could not locate the tree containing {}", f.display()),
+    }
+}
+
+/*
+ * Load the configuration file for a role directly from the tree, without a
+ * Context.  This is used by subcommands that do not touch the local system.
+ */
+pub fn read_config<T>(root: &Path, role: &str) -> Result<T>
+    where T: serde::de::DeserializeOwned,
+{
+    let p = root.join("config").join(format!("{}.toml", role));
+    let s = std::fs::read_to_string(&p)
+        .map_err(|e| anyhow!("reading {}: {}", p.display(), e))?;
+    toml::from_str(&s).map_err(|e| anyhow!("parsing {}: {}", p.display(), e))
+}
+
+/*
+ * Write a new file, readable only by its owner, replacing any file left at
+ * that path by an earlier run that did not finish.
+ */
+pub fn write_private(path: &Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+
+    let mut f = std::fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)?;
+    f.write_all(contents.as_bytes())?;
+    f.sync_all()?;
+
+    Ok(())
+}
+
+/*
+ * Install a file with the specified contents, which we have generated rather
+ * than copied from the files directory.  The contents are written to a private
+ * temporary file first, so that the usual checks and logging of ensure_file()
+ * apply.  Returns true if the file was changed.
+ */
+pub fn ensure_contents<P: AsRef<Path>>(c: &Context, contents: &str, dst: P,
+    owner: &str, group: &str, mode: u32) -> Result<bool>
+{
+    let tmp = PathBuf::from(format!("/var/run/.confomat.{}.tmp",
+        std::process::id()));
+    write_private(&tmp, contents)?;
+
+    let res = c.ensure_file(&tmp, dst, owner, group, mode, Create::Always);
+    std::fs::remove_file(&tmp)?;
+    res
+}
+
+/*
+ * Convert a date in "YYYY-MM-DD" form into a number of days since the UNIX
+ * epoch, so that dates may be compared and offset.
+ */
+pub fn parse_date(s: &str) -> Result<i64> {
+    let t: Vec<&str> = s.split('-').collect();
+    if t.len() != 3 || t[0].len() != 4 || t[1].len() != 2 || t[2].len() != 2
+        || !t.iter().all(|t| t.chars().all(|c| c.is_ascii_digit()))
+    {
+        bail!("invalid date {:?}; expected YYYY-MM-DD", s);
+    }
+
+    let y: i64 = t[0].parse()?;
+    let m: i64 = t[1].parse()?;
+    let d: i64 = t[2].parse()?;
+
+    let leap = (y % 4 == 0 && y % 100 != 0) || y % 400 == 0;
+    let mdays = match m {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => bail!("invalid date {:?}; month out of range", s),
+    };
+    if d < 1 || d > mdays {
+        bail!("invalid date {:?}; day out of range", s);
+    }
+
+    /*
+     * This is the "days_from_civil" algorithm described by Howard Hinnant.
+     */
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    Ok(era * 146097 + doe - 719468)
+}
+
+/*
+ * Convert a number of days since the UNIX epoch back into a year, month and
+ * day; the inverse of parse_date().
+ */
+pub fn civil_date(days: i64) -> (i64, i64, i64) {
+    /*
+     * This is the "civil_from_days" algorithm described by Howard Hinnant.
+     */
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400;
+
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/*
+ * The current date, as a number of days since the UNIX epoch.
+ */
+pub fn today() -> Result<i64> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?;
+    Ok((now.as_secs() / 86400) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2000-03-01").unwrap(), 11017);
+        assert_eq!(civil_date(11016), (2000, 2, 29));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+
+        for days in -800..30000 {
+            let (y, m, d) = civil_date(days);
+            let s = format!("{:04}-{:02}-{:02}", y, m, d);
+            assert_eq!(parse_date(&s).unwrap(), days, "{}", s);
+        }
+    }
+
+    #[test]
+    fn leap_days() {
+        assert!(parse_date("2024-02-29").is_ok());
+        assert!(parse_date("2000-02-29").is_ok());
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("1900-02-29").is_err());
+        assert_eq!(parse_date("2024-03-01").unwrap()
+            - parse_date("2024-02-28").unwrap(), 2);
+    }
+
+    #[test]
+    fn bad_dates() {
+        for s in &["+026-01-01", "2026-1-01", "2026-01-1", "2026/01/01",
+            "2026-00-01", "2026-13-01", "2026-04-31", "2026-01-00",
+            "2026-01-+1", " 026-01-01", ""]
+        {
+            assert!(parse_date(s).is_err(), "{}", s);
+        }
+    }
+}
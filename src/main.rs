@@ -1,14 +1,41 @@
 mod common;
 use common::*;
 
+mod nginx;
+
 mod role_users;
 mod role_www;
 mod role_base;
 mod role_pkgsrc;
 mod role_zfs_data;
 mod role_local_homedir;
+mod role_sshd;
+
+mod cmd_check;
+mod cmd_users;
 
 fn main() -> Result<()> {
+    /*
+     * Some subcommands operate only on the configuration in this tree, rather
+     * than applying roles to the local system:
+     */
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(cmd) = args.first() {
+        match cmd.as_str() {
+            "check" => return cmd_check::run(&args[1..]),
+            /*
+             * "users" is also the name of a role, so only treat it as a
+             * subcommand when followed by one of the users subcommands:
+             */
+            "users" if args.get(1).is_some_and(
+                |a| cmd_users::SUBCOMMANDS.contains(&a.as_str())) =>
+            {
+                return cmd_users::run(&args[1..]);
+            }
+            _ => (),
+        }
+    }
+
     let mut confomat = start()?;
 
     role_users::register(&mut confomat)?;
@@ -17,6 +44,7 @@ fn main() -> Result<()> {
     role_pkgsrc::register(&mut confomat)?;
     role_zfs_data::register(&mut confomat)?;
     role_local_homedir::register(&mut confomat)?;
+    role_sshd::register(&mut confomat)?;
 
     confomat.apply()?;
 
@@ -0,0 +1,437 @@
+use super::common::*;
+
+use std::path::{Path, PathBuf};
+
+/*
+ * A single directive, with its arguments and, for block directives like
+ * "server" or "location", the directives within the block.
+ */
+#[derive(Debug)]
+pub struct Directive {
+    pub name: String,
+    pub args: Vec<String>,
+    pub block: Option<Vec<Directive>>,
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Semicolon,
+    Open,
+    Close,
+}
+
+/*
+ * Split the configuration into words and punctuation, with the line number on
+ * which each token starts.  Quoted strings may contain whitespace and
+ * punctuation, and a backslash escapes the following character.
+ */
+fn tokenise(s: &str) -> Result<Vec<(Token, usize)>> {
+    let mut out = Vec::new();
+    let mut chars = s.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => (),
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            ';' => out.push((Token::Semicolon, line)),
+            '{' => out.push((Token::Open, line)),
+            '}' => out.push((Token::Close, line)),
+            '"' | '\'' => {
+                let start = line;
+                let mut w = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(e) => w.push(e),
+                            None => bail!("line {}: unterminated string",
+                                start),
+                        },
+                        Some(e) if e == c => break,
+                        Some(e) => {
+                            if e == '\n' {
+                                line += 1;
+                            }
+                            w.push(e);
+                        }
+                        None => bail!("line {}: unterminated string", start),
+                    }
+                }
+                out.push((Token::Word(w), start));
+            }
+            c => {
+                let mut w = String::new();
+                let mut c = Some(c);
+                while let Some(e) = c {
+                    if e == '\\' {
+                        if let Some(e) = chars.next() {
+                            w.push(e);
+                        }
+                    } else {
+                        w.push(e);
+                    }
+
+                    c = match chars.peek() {
+                        Some(&n) if n.is_whitespace()
+                            || ";{}".contains(n) => None,
+                        Some(_) => chars.next(),
+                        None => None,
+                    };
+                }
+                out.push((Token::Word(w), line));
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+fn parse_block<I>(toks: &mut I, file: &Path, nested: bool)
+    -> Result<Vec<Directive>>
+    where I: Iterator<Item = (Token, usize)>,
+{
+    let mut out = Vec::new();
+
+    loop {
+        let (name, line) = match toks.next() {
+            Some((Token::Word(w), line)) => (w, line),
+            Some((Token::Close, _)) if nested => return Ok(out),
+            Some((t, line)) => bail!("{}:{}: unexpected {:?}", file.display(),
+                line, t),
+            None if nested => bail!("{}: unexpected end of file in block",
+                file.display()),
+            None => return Ok(out),
+        };
+
+        let mut args = Vec::new();
+        let block = loop {
+            match toks.next() {
+                Some((Token::Word(w), _)) => args.push(w),
+                Some((Token::Semicolon, _)) => break None,
+                Some((Token::Open, _)) => {
+                    break Some(parse_block(toks, file, true)?);
+                }
+                Some((Token::Close, l)) => bail!("{}:{}: unexpected \"}}\"",
+                    file.display(), l),
+                None => bail!("{}:{}: directive {} is not terminated",
+                    file.display(), line, name),
+            }
+        };
+
+        out.push(Directive {
+            name,
+            args,
+            block,
+            file: file.to_path_buf(),
+            line,
+        });
+    }
+}
+
+/*
+ * Parse a single configuration file, without following includes.
+ */
+pub fn parse_file(file: &Path) -> Result<Vec<Directive>> {
+    let s = std::fs::read_to_string(file)
+        .map_err(|e| anyhow!("reading {}: {}", file.display(), e))?;
+    let toks = tokenise(&s)
+        .map_err(|e| anyhow!("{}: {}", file.display(), e))?;
+
+    parse_block(&mut toks.into_iter(), file, false)
+}
+
+/*
+ * Expand an include argument into the files it names.  A "*" in the last
+ * component of the path matches any sequence of characters, and a pattern
+ * that matches nothing is not an error.
+ */
+fn include_files(confdir: &Path, arg: &str) -> Result<Vec<PathBuf>> {
+    let p = confdir.join(arg);
+    let pat = p.file_name().map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !pat.contains('*') {
+        return Ok(vec![p]);
+    }
+
+    let parts: Vec<&str> = pat.split('*').collect();
+    let matches = |n: &str| -> bool {
+        let (first, last) = (parts[0], parts[parts.len() - 1]);
+        if !n.starts_with(first) || n.len() < first.len() + last.len()
+            || !n.ends_with(last)
+        {
+            return false;
+        }
+
+        let mut rest = &n[first.len()..n.len() - last.len()];
+        for mid in &parts[1..parts.len() - 1] {
+            match rest.find(mid) {
+                Some(i) => rest = &rest[i + mid.len()..],
+                None => return false,
+            }
+        }
+        true
+    };
+
+    let dir = p.parent().unwrap();
+    let mut out = Vec::new();
+    if let Ok(mut rd) = std::fs::read_dir(dir) {
+        while let Some(ent) = rd.next().transpose()? {
+            let n = ent.file_name().to_string_lossy().to_string();
+            if !n.starts_with('.') && matches(&n) {
+                out.push(ent.path());
+            }
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+/*
+ * The files and directories that a configuration needs in order to load and
+ * to do its job.
+ */
+#[derive(Debug, Default)]
+pub struct References {
+    /*
+     * Directories that must exist for access and error logs:
+     */
+    pub logdirs: Vec<PathBuf>,
+    /*
+     * Directories from which content is served with "root" or "alias":
+     */
+    pub docroots: Vec<PathBuf>,
+    /*
+     * Certificates, keys and other TLS files that nginx reads at startup:
+     */
+    pub files: Vec<(PathBuf, String)>,
+}
+
+const TLS_FILES: &[&str] = &[
+    "ssl_certificate",
+    "ssl_certificate_key",
+    "ssl_trusted_certificate",
+    "ssl_client_certificate",
+    "ssl_dhparam",
+];
+
+fn push<T: PartialEq>(v: &mut Vec<T>, t: T) {
+    if !v.contains(&t) {
+        v.push(t);
+    }
+}
+
+fn walk(confdir: &Path, dirs: &[Directive], refs: &mut References,
+    depth: usize) -> Result<()>
+{
+    if depth > 16 {
+        bail!("includes are nested too deeply");
+    }
+
+    for d in dirs {
+        let at = || format!("{}:{}", d.file.display(), d.line);
+
+        /*
+         * Paths that contain variables are only known at request time.
+         */
+        let arg = match d.args.first() {
+            Some(a) if !a.contains('$') => a.as_str(),
+            _ => "",
+        };
+
+        match d.name.as_str() {
+            "include" if !arg.is_empty() => {
+                for f in include_files(confdir, arg)? {
+                    if !f.exists() {
+                        bail!("{}: included file {} does not exist", at(),
+                            f.display());
+                    }
+                    let inc = parse_file(&f)?;
+                    walk(confdir, &inc, refs, depth + 1)?;
+                }
+            }
+            "access_log" | "error_log" if arg.starts_with('/') => {
+                if let Some(dir) = Path::new(arg).parent() {
+                    push(&mut refs.logdirs, dir.to_path_buf());
+                }
+            }
+            "root" | "alias" if arg.starts_with('/') => {
+                push(&mut refs.docroots, PathBuf::from(arg));
+            }
+            n if TLS_FILES.contains(&n) && !arg.is_empty()
+                && !arg.starts_with("data:") =>
+            {
+                push(&mut refs.files, (confdir.join(arg), at()));
+            }
+            _ => (),
+        }
+
+        if let Some(block) = &d.block {
+            walk(confdir, block, refs, depth)?;
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Read the main configuration file and every file it includes, relative paths
+ * being found in the directory of the main file, and collect the paths that
+ * the configuration refers to.
+ */
+pub fn references(main: &Path) -> Result<References> {
+    let confdir = main.parent().unwrap();
+    let mut refs = References::default();
+
+    let dirs = parse_file(main)?;
+    walk(confdir, &dirs, &mut refs, 0)?;
+
+    Ok(refs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(s: &str) -> Vec<(String, usize)> {
+        tokenise(s).unwrap().into_iter()
+            .filter_map(|(t, line)| match t {
+                Token::Word(w) => Some((w, line)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn parse(s: &str) -> Result<Vec<Directive>> {
+        parse_block(&mut tokenise(s)?.into_iter(), Path::new("test.conf"),
+            false)
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("confomat-nginx-{}-{}",
+            name, std::process::id()));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn quoted_strings() {
+        assert_eq!(words("return 200 \"a; {b}\" 'c d';"), vec![
+            ("return".to_string(), 1),
+            ("200".to_string(), 1),
+            ("a; {b}".to_string(), 1),
+            ("c d".to_string(), 1),
+        ]);
+    }
+
+    #[test]
+    fn multi_line_strings() {
+        assert_eq!(words("a \"one\ntwo\"\nb;"), vec![
+            ("a".to_string(), 1),
+            ("one\ntwo".to_string(), 1),
+            ("b".to_string(), 3),
+        ]);
+        assert!(tokenise("a \"one\ntwo;").is_err());
+    }
+
+    #[test]
+    fn escaped_characters() {
+        assert_eq!(words(r#"a "x\"y" b\;c\ d;"#), vec![
+            ("a".to_string(), 1),
+            ("x\"y".to_string(), 1),
+            ("b;c d".to_string(), 1),
+        ]);
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(words("# x;\nset $a#b 1; # c\n"), vec![
+            ("set".to_string(), 2),
+            ("$a#b".to_string(), 2),
+            ("1".to_string(), 2),
+        ]);
+    }
+
+    #[test]
+    fn blocks() {
+        let d = parse("http {\n  server {\n    listen 80;\n  }\n}\n")
+            .unwrap();
+        assert_eq!(d.len(), 1);
+        assert_eq!(d[0].name, "http");
+        let server = &d[0].block.as_ref().unwrap()[0];
+        assert_eq!(server.name, "server");
+        let listen = &server.block.as_ref().unwrap()[0];
+        assert_eq!((listen.name.as_str(), listen.args.clone(), listen.line),
+            ("listen", vec!["80".to_string()], 3));
+    }
+
+    #[test]
+    fn unterminated() {
+        assert!(parse("http {\n  server {\n  }\n").is_err());
+        assert!(parse("events {\n}\n}\n").is_err());
+        assert!(parse("listen 80").is_err());
+    }
+
+    #[test]
+    fn glob_includes() {
+        let dir = scratch("glob");
+        for n in &["a.conf", "b.conf", "b.conf.orig", ".c.conf", "d.txt"] {
+            std::fs::write(dir.join(n), "").unwrap();
+        }
+        std::fs::create_dir(dir.join("sites")).unwrap();
+        std::fs::write(dir.join("sites").join("x.y.conf"), "").unwrap();
+
+        let names = |pat: &str| -> Vec<String> {
+            include_files(&dir, pat).unwrap().iter()
+                .map(|p| p.strip_prefix(&dir).unwrap().display().to_string())
+                .collect()
+        };
+        assert_eq!(names("*.conf"), vec!["a.conf", "b.conf"]);
+        assert_eq!(names("b*"), vec!["b.conf", "b.conf.orig"]);
+        assert_eq!(names("sites/*.*.conf"), vec!["sites/x.y.conf"]);
+        assert_eq!(names("none/*.conf"), Vec::<String>::new());
+        assert_eq!(names("d.txt"), vec!["d.txt"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn references_follow_includes() {
+        let dir = scratch("refs");
+        std::fs::create_dir(dir.join("sites")).unwrap();
+        std::fs::write(dir.join("nginx.conf"), "\
+            error_log /var/log/nginx/error.log;\n\
+            http {\n\
+                include sites/*.conf;\n\
+            }\n").unwrap();
+        std::fs::write(dir.join("sites").join("a.conf"), "\
+            server {\n\
+                access_log /var/log/a/access.log;\n\
+                root /srv/a;\n\
+                ssl_certificate certs/a/fullchain.pem;\n\
+                location / { alias /srv/$host; }\n\
+            }\n").unwrap();
+
+        let refs = references(&dir.join("nginx.conf")).unwrap();
+        assert_eq!(refs.logdirs, vec![PathBuf::from("/var/log/nginx"),
+            PathBuf::from("/var/log/a")]);
+        assert_eq!(refs.docroots, vec![PathBuf::from("/srv/a")]);
+        assert_eq!(refs.files.len(), 1);
+        assert_eq!(refs.files[0].0, dir.join("certs/a/fullchain.pem"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,13 @@
 use super::common::*;
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    tar: String,
+    sha: String,
+    baseurl: String,
+}
 
 fn role_pkgsrc(c: &Context) -> Result<()> {
     let log = c.log();
@@ -12,12 +19,6 @@ fn role_pkgsrc(c: &Context) -> Result<()> {
         }
     }
 
-    #[derive(Debug, Deserialize)]
-    struct Config {
-        tar: String,
-        sha: String,
-        baseurl: String,
-    }
     let cfg: Config = c.config()?;
 
     let f = PathBuf::from(format!("/tmp/{}", cfg.tar));
@@ -33,6 +34,27 @@ fn role_pkgsrc(c: &Context) -> Result<()> {
     Ok(())
 }
 
+pub fn check(root: &Path) -> Result<Vec<String>> {
+    let cfg: Config = read_config(root, "pkgsrc")?;
+    let mut problems = Vec::new();
+
+    if cfg.sha.len() != 40 || !cfg.sha.chars().all(|c| c.is_ascii_hexdigit()) {
+        problems.push(format!("sha {:?} is not a SHA-1 digest", cfg.sha));
+    }
+    if !cfg.baseurl.starts_with("https://") {
+        problems.push(format!("baseurl {:?} is not a HTTPS URL", cfg.baseurl));
+    }
+    if !cfg.baseurl.ends_with('/') {
+        problems.push(format!("baseurl {:?} must end with \"/\"",
+            cfg.baseurl));
+    }
+    if cfg.tar.is_empty() || cfg.tar.contains('/') {
+        problems.push(format!("tar {:?} is not a file name", cfg.tar));
+    }
+
+    Ok(problems)
+}
+
 pub fn register(confomat: &mut Confomat) -> Result<()> {
     confomat.register(&RoleProvider {
         name: "pkgsrc",
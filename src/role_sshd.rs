@@ -0,0 +1,210 @@
+use super::common::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::role_users;
+
+const SSHD: &str = "/usr/lib/ssh/sshd";
+const SSHD_CONFIG: &str = "/etc/ssh/sshd_config";
+const FRAGMENT_DIR: &str = "/etc/ssh/sshd_config.d";
+const FRAGMENT: &str = "/etc/ssh/sshd_config.d/confomat.conf";
+
+const CLASSES: &[&str] = &["ops", "general", "service"];
+
+/*
+ * The users that may log in are those with an account on the host that are
+ * in one of the listed classes or groups, or are listed by name.  Entries
+ * under a host name are added to the global lists on that host.
+ */
+#[derive(Debug, Deserialize, Default)]
+struct FileAllow {
+    classes: Option<Vec<String>>,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    users: Vec<String>,
+}
+
+/*
+ * Accounts that are not in the users configuration, but must always be able
+ * to log in; e.g., root, which is used by the deployment tooling.
+ */
+fn always_allow() -> Vec<String> {
+    vec!["root".into()]
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    #[serde(default = "always_allow")]
+    always_allow: Vec<String>,
+    #[serde(flatten)]
+    allow: FileAllow,
+    #[serde(default)]
+    hosts: HashMap<String, FileAllow>,
+}
+
+impl Config {
+    /*
+     * Service accounts are not interactive, so unless the configuration says
+     * otherwise only operators and general users may log in.
+     */
+    fn classes(&self) -> Vec<String> {
+        self.allow.classes.clone()
+            .unwrap_or_else(|| vec!["ops".into(), "general".into()])
+    }
+}
+
+/*
+ * Determine the users that may log in to the host with this node name.
+ */
+fn allowed(root: &Path, cfg: &Config, nodename: &str) -> Result<Vec<String>> {
+    let none = FileAllow::default();
+    let host = cfg.hosts.get(nodename).unwrap_or(&none);
+
+    let mut classes = cfg.classes();
+    classes.extend(host.classes.iter().flatten().cloned());
+    let groups: Vec<&String> = cfg.allow.groups.iter()
+        .chain(host.groups.iter()).collect();
+    let users: Vec<&String> = cfg.allow.users.iter()
+        .chain(host.users.iter()).collect();
+
+    let mut out = cfg.always_allow.clone();
+    for lu in role_users::login_users(root, nodename)? {
+        if !out.contains(&lu.name) && (classes.contains(&lu.class)
+            || users.contains(&&lu.name)
+            || lu.groups.iter().any(|g| groups.contains(&g)))
+        {
+            out.push(lu.name);
+        }
+    }
+
+    Ok(out)
+}
+
+fn role_sshd(c: &Context) -> Result<()> {
+    let log = c.log();
+
+    let cfg: Config = c.config()?;
+    let nodename = nodename(c)?;
+    let root = context_root(c, "confomat.conf")?;
+
+    let users = allowed(&root, &cfg, &nodename)?;
+    if users.is_empty() {
+        /*
+         * An empty list would leave nobody able to log in at all.
+         */
+        bail!("no users may log in to {}; refusing to lock out ssh",
+            nodename);
+    }
+    info!(log, "users allowed to log in: {}", users.join(" "));
+
+    /*
+     * OpenSSH requires a user to match both AllowUsers and AllowGroups when
+     * both are present, so group grants have been expanded into the list of
+     * users rather than written as AllowGroups.
+     */
+    let mut fragment = std::fs::read_to_string(c.file("confomat.conf")?)?;
+    fragment.push_str(&format!("AllowUsers {}\n", users.join(" ")));
+
+    /*
+     * Include the fragment at the top of the main configuration file, ahead
+     * of any directives that are already there:
+     */
+    let include = format!("Include {}", FRAGMENT);
+    let lines = c.read_lines(SSHD_CONFIG)?.unwrap_or_default();
+    let mut main = String::new();
+    if !lines.iter().any(|l| l.trim() == include) {
+        main.push_str(&format!("{}\n", include));
+    }
+    for l in lines.iter() {
+        main.push_str(&format!("{}\n", l));
+    }
+
+    /*
+     * Check the complete configuration we are about to install, using copies
+     * that include the new fragment, before changing any of the live files.
+     */
+    let test = format!("/var/run/.confomat.sshd.{}", std::process::id());
+    let testfrag = format!("{}.conf", test);
+    let testmain = main.lines()
+        .map(|l| if l.trim() == include {
+            format!("Include {}\n", testfrag)
+        } else {
+            format!("{}\n", l)
+        })
+        .collect::<String>();
+    write_private(Path::new(&testfrag), &fragment)?;
+    write_private(Path::new(&test), &testmain)?;
+    let res = c.run(&[SSHD, "-t", "-f", &test]);
+    std::fs::remove_file(&test)?;
+    std::fs::remove_file(&testfrag)?;
+    if let Err(e) = res {
+        bail!("new sshd configuration is not valid: {}", e);
+    }
+
+    c.ensure_dir(FRAGMENT_DIR, ROOT, "sys", 0o755)?;
+    let mut changed = ensure_contents(c, &fragment, FRAGMENT, ROOT, "sys",
+        0o644)?;
+    if ensure_contents(c, &main, SSHD_CONFIG, ROOT, "sys", 0o644)? {
+        changed = true;
+    }
+
+    if changed {
+        info!(log, "refreshing ssh service...");
+        c.run(&["/usr/sbin/svcadm", "refresh", "svc:/network/ssh:default"])?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Validate the sshd configuration against the users configuration.
+ */
+pub fn check(root: &Path) -> Result<Vec<String>> {
+    let cfg: Config = read_config(root, "sshd")?;
+    let (users, groups) = role_users::names(root)?;
+    let mut problems = Vec::new();
+
+    for u in cfg.always_allow.iter() {
+        if u.is_empty() || u.contains(char::is_whitespace) {
+            problems.push(format!("always_allow user {:?} is not valid", u));
+        }
+    }
+
+    let hosts = cfg.hosts.iter().map(|(n, a)| (format!("host {}", n), a));
+    for (what, a) in std::iter::once(("global".to_string(), &cfg.allow))
+        .chain(hosts)
+    {
+        for class in a.classes.iter().flatten() {
+            if !CLASSES.contains(&class.as_str()) {
+                problems.push(format!("{} class {:?} is not a user class",
+                    what, class));
+            }
+        }
+
+        for u in a.users.iter() {
+            if !users.contains(u) {
+                problems.push(format!("{} user {} is not a configured user",
+                    what, u));
+            }
+        }
+
+        for g in a.groups.iter() {
+            if !groups.contains(g) {
+                problems.push(format!("{} group {} is not a configured group",
+                    what, g));
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+pub fn register(confomat: &mut Confomat) -> Result<()> {
+    confomat.register(&RoleProvider {
+        name: "sshd",
+        func: role_sshd,
+        instance_posture: InstancePosture::Prohibited,
+    })
+}
@@ -1,8 +1,9 @@
 use super::common::*;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
@@ -13,6 +14,241 @@ enum UserClass {
     Service,
 }
 
+impl UserClass {
+    fn from_name(name: &str) -> Result<UserClass> {
+        Ok(match name {
+            "ops" => UserClass::Ops,
+            "general" => UserClass::General,
+            "service" => UserClass::Service,
+            n => bail!("unknown user class {:?}", n),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            UserClass::Ops => "ops",
+            UserClass::General => "general",
+            UserClass::Service => "service",
+        }
+    }
+}
+
+/*
+ * How a dotfile should be installed in a home directory.  Files are normally
+ * only installed if missing, so that users may change them; "always" enforces
+ * our copy, and "never" suppresses a file provided by a lower layer.
+ */
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+enum DotfileCreate {
+    Always,
+    IfMissing,
+    Never,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileDotfile {
+    create: DotfileCreate,
+}
+
+/*
+ * Each dotfiles directory may contain a "manifest.toml", with an entry for
+ * each file name that needs other than the default treatment.
+ */
+type FileManifest = HashMap<String, FileDotfile>;
+
+/*
+ * The desired state of an account.  Entries for departed users and groups are
+ * kept in the file with a state other than "present" so that we know to
+ * remove them from every host, and so that their IDs are not reused.
+ */
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+enum State {
+    Present,
+    Locked,
+    Absent,
+}
+
+/*
+ * What to do with the ZFS home dataset of a user that has been removed.
+ */
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+enum HomeDisposal {
+    Keep,
+    Archive,
+    Destroy,
+}
+
+/*
+ * How the password for an account should be set.  Accounts either have no
+ * password at all (and thus may only log in with SSH keys), have a locked
+ * password, or use a password hash from the secrets file.
+ */
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+enum PasswordPolicy {
+    NP,
+    LK,
+    Hash,
+}
+
+/*
+ * Settings that apply to every user of a particular class, unless overridden
+ * for a specific user.
+ */
+#[derive(Debug, Deserialize, Default)]
+struct FileClass {
+    password: Option<PasswordPolicy>,
+    zfs: Option<FileZfs>,
+    /*
+     * The inclusive range of uids from which "confomat users add" allocates,
+     * and the primary group it uses when none is given:
+     */
+    uid_range: Option<(i64, i64)>,
+    group: Option<String>,
+}
+
+/*
+ * Properties for the ZFS home dataset of a user.  Any property that is not
+ * specified is left alone.
+ */
+#[derive(Debug, Deserialize, Default, Clone)]
+struct FileZfs {
+    quota: Option<String>,
+    reservation: Option<String>,
+    compression: Option<String>,
+    recordsize: Option<String>,
+}
+
+impl FileZfs {
+    /*
+     * Produce the list of properties to set, preferring the values in this
+     * (per-user) set and falling back to those from the class.
+     */
+    fn merge(&self, class: &FileZfs) -> BTreeMap<String, String> {
+        let mut out = BTreeMap::new();
+        let mut add = |n: &str, a: &Option<String>, b: &Option<String>| {
+            if let Some(v) = a.as_ref().or(b.as_ref()) {
+                out.insert(n.to_string(), v.to_string());
+            }
+        };
+
+        add("quota", &self.quota, &class.quota);
+        add("reservation", &self.reservation, &class.reservation);
+        add("compression", &self.compression, &class.compression);
+        add("recordsize", &self.recordsize, &class.recordsize);
+
+        out
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct FileClasses {
+    ops: Option<FileClass>,
+    general: Option<FileClass>,
+    service: Option<FileClass>,
+}
+
+impl FileClasses {
+    fn get(&self, class: &UserClass) -> Option<&FileClass> {
+        match class {
+            UserClass::Ops => self.ops.as_ref(),
+            UserClass::General => self.general.as_ref(),
+            UserClass::Service => self.service.as_ref(),
+        }
+    }
+}
+
+/*
+ * A single key from "ssh_keys/<user>.toml", which may carry options that
+ * restrict its use and a date from which it is no longer installed.  As with
+ * the expires date of an account, the key is not usable on that date.
+ */
+#[derive(Debug, Deserialize)]
+struct FileKey {
+    key: String,
+    comment: Option<String>,
+    #[serde(default)]
+    from: Vec<String>,
+    command: Option<String>,
+    expires: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileKeys {
+    #[serde(default)]
+    keys: Vec<FileKey>,
+}
+
+impl FileKey {
+    /*
+     * Check that the key can be written safely as a single line.  A newline in
+     * any field would add another key without our restrictions, and a quote in
+     * a "from" pattern would end the option early.
+     */
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let what = self.comment.as_deref().unwrap_or("-");
+
+        let t: Vec<&str> = self.key.split_whitespace().collect();
+        let b64 = |s: &str| !s.is_empty() && s.chars().all(|c| {
+            c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '='
+        });
+        if t.len() != 2 || !t[0].chars().all(|c| c.is_ascii_alphanumeric()
+            || c == '-' || c == '@' || c == '.') || !b64(t[1])
+        {
+            problems.push(format!("key {} is not of the form \
+                \"<type> <base64>\"", what));
+        }
+
+        let fields = std::iter::once(&self.key)
+            .chain(self.comment.iter())
+            .chain(self.from.iter())
+            .chain(self.command.iter());
+        if fields.clone().any(|f| f.contains('\n') || f.contains('\r')) {
+            problems.push(format!("key {} contains a newline", what));
+        }
+
+        if self.from.iter().any(|f| f.contains('"') || f.is_empty()) {
+            problems.push(format!("key {} has an invalid from pattern", what));
+        }
+
+        if let Some(Err(e)) = self.expires.as_deref().map(parse_date) {
+            problems.push(format!("key {} expires: {}", what, e));
+        }
+
+        problems
+    }
+
+    /*
+     * Produce the line for this key in authorized_keys(5) format.
+     */
+    fn line(&self) -> String {
+        let mut opts: Vec<String> = Vec::new();
+        if !self.from.is_empty() {
+            opts.push(format!("from=\"{}\"", self.from.join(",")));
+        }
+        if let Some(cmd) = &self.command {
+            opts.push(format!("command=\"{}\"", cmd.replace('"', "\\\"")));
+        }
+
+        let mut l = String::new();
+        if !opts.is_empty() {
+            l.push_str(&opts.join(","));
+            l.push(' ');
+        }
+        l.push_str(self.key.trim());
+        if let Some(comment) = &self.comment {
+            l.push(' ');
+            l.push_str(comment);
+        }
+        l.push('\n');
+        l
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct FileUser {
     class: UserClass,
@@ -22,11 +258,55 @@ struct FileUser {
     home: Option<String>,
     home_create: Option<bool>,
     profiles: Option<Vec<String>>,
+    hosts: Option<Vec<String>>,
+    host_groups: Option<Vec<String>>,
+    state: Option<State>,
+    home_disposal: Option<HomeDisposal>,
+    shell: Option<String>,
+    groups: Option<Vec<String>>,
+    password: Option<PasswordPolicy>,
+    zfs: Option<FileZfs>,
+    home_dataset: Option<String>,
+    auths: Option<Vec<String>>,
+    roles: Option<Vec<String>>,
+    defaultpriv: Option<Vec<String>>,
+    limitpriv: Option<Vec<String>>,
+    expires: Option<String>,
+    not_before: Option<String>,
+    cron: Option<BTreeMap<String, FileCron>>,
+    project: Option<FileProject>,
+}
+
+/*
+ * A scheduled job to be installed in the crontab of a user.  The schedule is
+ * the usual five time and date fields of crontab(1).
+ */
+#[derive(Debug, Deserialize)]
+struct FileCron {
+    schedule: String,
+    command: String,
+}
+
+/*
+ * A project(4) for a service user, with the resource controls that apply to
+ * it.  Resource control values are either a full action list like
+ * "(privileged,100,deny)", or a bare number as a shorthand for a privileged
+ * limit with the deny action.
+ */
+#[derive(Debug, Deserialize)]
+struct FileProject {
+    projid: i64,
+    name: Option<String>,
+    comment: Option<String>,
+    #[serde(default)]
+    rctls: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct FileGroup {
     gid: i64,
+    state: Option<State>,
+    members: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -35,6 +315,49 @@ struct ConfomatToml {
     users: HashMap<String, FileUser>,
     #[serde(default)]
     groups: HashMap<String, FileGroup>,
+    #[serde(default)]
+    host_groups: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    classes: FileClasses,
+    #[serde(default)]
+    profiles: BTreeMap<String, FileProfile>,
+    expiry_warning_days: Option<i64>,
+}
+
+/*
+ * A locally defined RBAC profile, which is written to prof_attr(4) and
+ * exec_attr(4) so that narrow privileges can be delegated to users.
+ */
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct FileProfile {
+    desc: Option<String>,
+    #[serde(default)]
+    auths: Vec<String>,
+    #[serde(default)]
+    profiles: Vec<String>,
+    #[serde(default)]
+    exec: Vec<FileExec>,
+}
+
+/*
+ * A command that may be executed with particular attributes (e.g., "euid" or
+ * "privs") by users with this profile.
+ */
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct FileExec {
+    command: String,
+    #[serde(default)]
+    attrs: BTreeMap<String, String>,
+}
+
+/*
+ * The secrets file is kept separately from the configuration, and is not
+ * committed to the repository.
+ */
+#[derive(Debug, Deserialize, Default)]
+struct SecretsToml {
+    #[serde(default)]
+    password_hash: HashMap<String, String>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -47,230 +370,2257 @@ struct User {
     home: String,
     home_create: bool,
     profiles: Vec<String>,
+    hosts: Vec<String>,
+    state: State,
+    home_disposal: HomeDisposal,
+    shell: String,
+    groups: Vec<String>,
+    password: PasswordPolicy,
+    zfs: BTreeMap<String, String>,
+    home_dataset: Option<String>,
+    auths: Vec<String>,
+    roles: Vec<String>,
+    defaultpriv: Option<Vec<String>>,
+    limitpriv: Option<Vec<String>>,
+    expires: Option<i64>,
+    not_before: Option<i64>,
+    cron: BTreeMap<String, String>,
+    project: Option<Project>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
-struct Group {
+struct Project {
     name: String,
-    gid: i64,
+    projid: i64,
+    comment: String,
+    rctls: BTreeMap<String, String>,
 }
 
-#[derive(Debug, PartialEq, Clone)]
-struct UsersConfig {
-    users: HashMap<String, User>,
-    groups: HashMap<String, Group>,
+impl Project {
+    /*
+     * Render the entry for this project in the project(4) database.
+     */
+    fn line(&self, user: &str) -> String {
+        let attrs: Vec<String> = self.rctls.iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        format!("{}:{}:{}:{}::{}", self.name, self.projid, self.comment, user,
+            attrs.join(";"))
+    }
 }
 
-fn load(toml: ConfomatToml) -> Result<UsersConfig> {
-    let mut users = HashMap::new();
-    let mut groups = HashMap::new();
-
-    for (k, v) in &toml.users {
-        users.insert(k.to_string(), User {
-            name: k.to_string(),
-            uid: v.uid,
-            class: v.class.clone(),
-            /*
-             * If no particular gecos value is specified, just use the
-             * username:
-             */
-            gecos: v.gecos.as_ref()
-                .map_or_else(|| k.to_string(), |s| s.to_string()),
-            group: v.group.to_string(),
+impl User {
+    /*
+     * Determine whether this user should have an account on the host with the
+     * specified node name.
+     */
+    fn applies_to(&self, nodename: &str) -> bool {
+        match self.class {
             /*
-             * If no home directory is specified, use "/home/$USER":
+             * Service and operator accounts are created on every host.
              */
-            home: v.home.as_ref()
-                .map_or_else(|| format!("/home/{}", k), |s| s.to_string()),
+            UserClass::Ops | UserClass::Service => true,
             /*
-             * If "home_create" does not appear in the file, we
-             * default to creating the home directory:
+             * General class users are created only on the hosts that appear
+             * in their host list, either directly or through a host group.
              */
-            home_create: v.home_create.unwrap_or(true),
-            profiles: v.profiles.as_ref()
-                .map_or_else(|| vec![], |v| v.clone()),
-        });
+            UserClass::General => self.hosts.iter().any(|h| h == nodename),
+        }
     }
 
-    for (k, v) in &toml.groups {
-        groups.insert(k.to_string(), Group {
-            name: k.to_string(),
-            gid: v.gid,
-        });
+    /*
+     * Determine whether the account falls within its access window on the
+     * specified day.  As with the expire field of shadow(4), the account can
+     * no longer be used from its expiry date onwards.
+     */
+    fn active(&self, today: i64) -> bool {
+        if self.not_before.is_some_and(|nb| today < nb) {
+            return false;
+        }
+
+        if self.expires.is_some_and(|ex| today >= ex) {
+            return false;
+        }
+
+        true
     }
+}
 
-    Ok(UsersConfig {
-        users,
-        groups,
-    })
+#[derive(Debug, PartialEq, Clone)]
+struct Group {
+    name: String,
+    gid: i64,
+    state: State,
+    members: Vec<String>,
 }
 
-fn role_users(c: &Context) -> Result<()> {
-    let log = c.log();
+#[derive(Debug, PartialEq, Clone)]
+struct UsersConfig {
+    users: HashMap<String, User>,
+    groups: HashMap<String, Group>,
+    profiles: BTreeMap<String, FileProfile>,
+    expiry_warning_days: i64,
+}
 
+impl UsersConfig {
     /*
-     * Load configuration from the data directory:
+     * Groups and users are processed in order of gid and uid respectively, so
+     * that each run on each host does the same work in the same order.
      */
-    let cfg = load(c.config()?)?;
+    fn groups_sorted(&self) -> Vec<&Group> {
+        let mut out: Vec<&Group> = self.groups.values().collect();
+        out.sort_by(|a, b| (a.gid, &a.name).cmp(&(b.gid, &b.name)));
+        out
+    }
 
-    /*
-     * Check for UNIX groups...
-     */
-    info!(log, "processing groups...");
-    for g in cfg.groups.values() {
-        let g: &Group = g;
+    fn users_sorted(&self) -> Vec<&User> {
+        let mut out: Vec<&User> = self.users.values().collect();
+        out.sort_by(|a, b| (a.uid, &a.name).cmp(&(b.uid, &b.name)));
+        out
+    }
+}
 
-        info!(log, "group {} gid {}", g.name, g.gid);
+/*
+ * An entry from the local passwd(4) file.
+ */
+#[derive(Debug, PartialEq, Clone)]
+struct PasswdEntry {
+    name: String,
+    uid: i64,
+    gid: i64,
+    gecos: String,
+    home: String,
+    shell: String,
+}
 
-        if illumos::get_group_by_name(&g.name)?.is_some() {
-            info!(log, "group {} exists!", &g.name);
-        } else {
-            info!(log, "group {} not found, creating...", &g.name);
-            c.run(&["/usr/sbin/groupadd", "-g", &g.gid.to_string(), &g.name])?;
+/*
+ * An entry from the local group(4) file.
+ */
+#[derive(Debug, PartialEq, Clone)]
+struct GroupEntry {
+    name: String,
+    gid: i64,
+    members: Vec<String>,
+}
+
+/*
+ * Read a colon-separated system database file, skipping any NIS
+ * compatibility entries.
+ */
+fn read_db(c: &Context, path: &str, nfields: usize)
+    -> Result<Vec<Vec<String>>>
+{
+    let lines = if let Some(lines) = c.read_lines(path)? {
+        lines
+    } else {
+        bail!("{} is missing", path);
+    };
+
+    let mut out = Vec::new();
+    for l in lines {
+        if l.trim().is_empty() || l.starts_with('+') || l.starts_with('-') {
+            continue;
+        }
+
+        let t: Vec<String> = l.split(':').map(|s| s.to_string()).collect();
+        if t.len() != nfields {
+            bail!("{}: malformed line: {}", path, l);
         }
+        out.push(t);
     }
 
-    /*
-     * Check for UNIX users...
-     */
-    info!(log, "processing users...");
-    for u in cfg.users.values() {
-        let u: &User = u;
+    Ok(out)
+}
 
-        info!(log, "user {} uid {}", &u.name, &u.uid);
+fn read_passwd(c: &Context) -> Result<Vec<PasswdEntry>> {
+    read_db(c, "/etc/passwd", 7)?.into_iter().map(|t| -> Result<_> {
+        Ok(PasswdEntry {
+            name: t[0].to_string(),
+            uid: t[2].parse()?,
+            gid: t[3].parse()?,
+            gecos: t[4].to_string(),
+            home: t[5].to_string(),
+            shell: t[6].to_string(),
+        })
+    }).collect()
+}
 
-        match u.class {
-            /*
-             * Service and operator accounts are created on every host.
-             */
-            UserClass::Ops | UserClass::Service => (),
-            /*
-             * XXX For now, skip general class users altogether.
-             */
-            UserClass::General => continue,
-        }
+fn read_group(c: &Context) -> Result<Vec<GroupEntry>> {
+    read_db(c, "/etc/group", 4)?.into_iter().map(|t| -> Result<_> {
+        Ok(GroupEntry {
+            name: t[0].to_string(),
+            gid: t[2].parse()?,
+            members: t[3].split(',')
+                .filter(|m| !m.is_empty())
+                .map(|m| m.to_string())
+                .collect(),
+        })
+    }).collect()
+}
 
-        if illumos::get_passwd_by_name(&u.name)?.is_some() {
-            info!(log, "user {} exists!", &u.name);
-        } else {
-            info!(log, "user {} not found, creating...", &u.name);
-            c.run(&["/usr/sbin/useradd",
-                "-u", &u.uid.to_string(),
-                "-g", &u.group,
-                "-d", &u.home,
-                "-s", "/bin/bash",
-                "-c", &u.gecos,
-                &u.name])?;
+/*
+ * An entry from the local shadow(4) file.  We only need the password field.
+ */
+#[derive(Debug, PartialEq, Clone)]
+struct ShadowEntry {
+    name: String,
+    password: String,
+    expire: Option<i64>,
+}
+
+fn read_shadow(c: &Context) -> Result<Vec<ShadowEntry>> {
+    Ok(read_db(c, "/etc/shadow", 9)?.into_iter().map(|t| {
+        ShadowEntry {
+            name: t[0].to_string(),
+            password: t[1].to_string(),
+            expire: t[7].parse().ok(),
         }
+    }).collect())
+}
 
-        info!(log, "user {}: setting nopassword...", &u.name);
-        c.run(&["/bin/passwd", "-N", &u.name])?;
+/*
+ * Split a user_attr(4) style string on a separator character, honouring
+ * backslash escapes.
+ */
+fn split_escaped(s: &str, sep: char) -> Vec<String> {
+    let mut out = vec![String::new()];
+    let mut esc = false;
 
-        /*
-         * We take extra care to avoid running "usermod -P" here if we do not
-         * need to adjust the profile list.  This tool appears to alter the
-         * database whether or not it needed to do so, which causes nscd to drop
-         * its cache on the floor for two seconds (to debounce file
-         * modifications) and holds up each turn of this loop.
-         */
-        let uap = if let Some(ua) = illumos::get_user_attr_by_name(&u.name)? {
-            info!(log, "user attributes: {:#?}", ua);
-            ua.profiles()
+    for ch in s.chars() {
+        if esc {
+            out.last_mut().unwrap().push(ch);
+            esc = false;
+        } else if ch == '\\' {
+            esc = true;
+        } else if ch == sep {
+            out.push(String::new());
         } else {
-            info!(log, "no user attributes?");
-            Vec::new()
-        };
+            out.last_mut().unwrap().push(ch);
+        }
+    }
 
-        if uap != u.profiles {
-            let profiles = u.profiles.iter().fold("".to_string(), |mut l, p| {
-                if !l.is_empty() {
-                    l.push_str(",");
-                }
-                l.push_str(p);
-                l
-            });
+    out
+}
 
-            info!(log, "user {}: fixing profiles...", &u.name);
-            c.run(&["/usr/sbin/usermod", "-P", &profiles, &u.name])?;
+/*
+ * Read the attributes assigned to each user in the local user_attr(4) file.
+ */
+fn read_user_attr(c: &Context)
+    -> Result<BTreeMap<String, BTreeMap<String, String>>>
+{
+    let mut out = BTreeMap::new();
+
+    let lines = c.read_lines("/etc/user_attr")?.unwrap_or_default();
+    for l in lines {
+        if l.trim().is_empty() || l.starts_with('#') {
+            continue;
         }
 
-        if !u.home_create {
-            /*
-             * No home directory creation required for this user.
-             */
+        let t = split_escaped(&l, ':');
+        if t.len() != 5 {
             continue;
         }
 
-        match c.homedir()? {
-            HomeDir::NFS => {
-                /*
-                 * We assume, for now at least, that no action is required on a
-                 * system with automounted home directories.
-                 *
-                 * In particular, we won't reach into the NFS home directory and
-                 * (re)create any profile files or potentially damage the
-                 * contents in any way.
-                 */
-                continue;
+        let attrs: &mut BTreeMap<String, String> = out
+            .entry(t[0].to_string())
+            .or_default();
+        for kv in split_escaped(&t[4], ';') {
+            let mut kv = kv.splitn(2, '=');
+            if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                attrs.insert(k.to_string(), v.to_string());
             }
-            HomeDir::ZFS(dataset) => {
-                /*
-                 * We currently only support the simplest possible layout; i.e.,
-                 * rpool/home/$USER => /home/$USER.  Confirm that the home
-                 * directory path is as expected...
-                 */
-                let expect = format!("/home/{}", &u.name);
-                if u.home != expect {
-                    bail!("user {} with unsupported ZFS home \
-                        directory path: {}", &u.name, &u.home);
-                }
+        }
+    }
 
-                /*
-                 * Ensure that the dataset exists:
-                 */
-                let dsname = format!("{}/{}", dataset, &u.name);
-                c.ensure_dataset(&dsname, &[])?;
-            }
-            HomeDir::Bare => {
-                /*
-                 * On systems where neither NFS nor ZFS home directory
+    Ok(out)
+}
+
+/*
+ * A snapshot of the local account databases.  We read each database once,
+ * rather than once per lookup, which makes a run in which nothing needs to
+ * change much faster.  Whenever we run a command that modifies the databases
+ * we mark the snapshot as stale, and it is read again before the next use.
+ */
+struct Accounts {
+    passwd: Vec<PasswdEntry>,
+    group: Vec<GroupEntry>,
+    shadow: Vec<ShadowEntry>,
+    user_attr: BTreeMap<String, BTreeMap<String, String>>,
+    datasets: Option<Vec<(String, String)>>,
+    stale: bool,
+}
+
+impl Accounts {
+    fn load(c: &Context) -> Result<Accounts> {
+        Ok(Accounts {
+            passwd: read_passwd(c)?,
+            group: read_group(c)?,
+            shadow: read_shadow(c)?,
+            user_attr: read_user_attr(c)?,
+            datasets: None,
+            stale: false,
+        })
+    }
+
+    fn refresh(&mut self, c: &Context) -> Result<()> {
+        if self.stale {
+            let datasets = self.datasets.take();
+            *self = Accounts::load(c)?;
+            self.datasets = datasets;
+        }
+        Ok(())
+    }
+
+    /*
+     * The mounted file systems are not changed by account commands, so we
+     * list them at most once per run, and only if a home directory needs a
+     * dataset.
+     */
+    fn datasets(&mut self) -> Result<&[(String, String)]> {
+        if self.datasets.is_none() {
+            self.datasets = Some(mounted_datasets()?);
+        }
+        Ok(self.datasets.as_deref().unwrap())
+    }
+
+    /*
+     * Run a command that modifies the account databases, and mark the
+     * snapshot stale.
+     */
+    fn run(&mut self, c: &Context, args: &[&str]) -> Result<()> {
+        self.stale = true;
+        c.run(args)?;
+        Ok(())
+    }
+
+    fn passwd(&self, name: &str) -> Option<&PasswdEntry> {
+        self.passwd.iter().find(|pw| pw.name == name)
+    }
+
+    fn group(&self, name: &str) -> Option<&GroupEntry> {
+        self.group.iter().find(|ge| ge.name == name)
+    }
+
+    fn shadow_password(&self, name: &str) -> Result<&str> {
+        if let Some(se) = self.shadow.iter().find(|se| se.name == name) {
+            Ok(&se.password)
+        } else {
+            bail!("user {} not found in /etc/shadow", name);
+        }
+    }
+
+    fn shadow_expire(&self, name: &str) -> Result<Option<i64>> {
+        if let Some(se) = self.shadow.iter().find(|se| se.name == name) {
+            Ok(se.expire)
+        } else {
+            bail!("user {} not found in /etc/shadow", name);
+        }
+    }
+
+    fn attrs(&self, name: &str) -> BTreeMap<String, String> {
+        self.user_attr.get(name).cloned().unwrap_or_default()
+    }
+}
+
+/*
+ * Replace the password field of a single entry in the shadow(4) file.
+ */
+fn write_shadow_password(c: &Context, name: &str, password: &str)
+    -> Result<()>
+{
+    if password.contains(':') || password.contains('\n') {
+        bail!("invalid password field for user {}", name);
+    }
+
+    let lines = if let Some(lines) = c.read_lines("/etc/shadow")? {
+        lines
+    } else {
+        bail!("/etc/shadow is missing");
+    };
+
+    let mut found = false;
+    let mut out = String::new();
+    for l in lines {
+        let mut t: Vec<&str> = l.split(':').collect();
+        if t.len() == 9 && t[0] == name {
+            t[1] = password;
+            found = true;
+        }
+        out.push_str(&t.join(":"));
+        out.push('\n');
+    }
+
+    if !found {
+        bail!("user {} not found in /etc/shadow", name);
+    }
+
+    ensure_contents(c, &out, "/etc/shadow", ROOT, "sys", 0o400)?;
+
+    Ok(())
+}
+
+/*
+ * Load password hashes from the secrets file, if there is one.
+ */
+fn load_secrets(c: &Context) -> Result<SecretsToml> {
+    if let Some(f) = c.file_maybe("secrets.toml")? {
+        let s = std::fs::read_to_string(&f)?;
+        Ok(toml::from_str(&s)?)
+    } else {
+        Ok(SecretsToml::default())
+    }
+}
+
+fn load(toml: ConfomatToml) -> Result<UsersConfig> {
+    let (cfg, problems) = load_checked(toml);
+    if !problems.is_empty() {
+        bail!("{}", problems.join("; "));
+    }
+    Ok(cfg)
+}
+
+/*
+ * Interpret the contents of users.toml, collecting every problem we find
+ * rather than stopping at the first, so that "confomat check" can report
+ * them all.  Entries with problems are included as best we can.
+ */
+fn load_checked(toml: ConfomatToml) -> (UsersConfig, Vec<String>) {
+    let mut users = HashMap::new();
+    let mut groups = HashMap::new();
+    let mut problems = Vec::new();
+
+    for (k, v) in &toml.users {
+        /*
+         * Expand any host groups into the list of hosts on which this user
+         * should have an account:
+         */
+        let mut hosts = v.hosts.clone().unwrap_or_default();
+        for hg in v.host_groups.clone().unwrap_or_default() {
+            if let Some(hgl) = toml.host_groups.get(&hg) {
+                for h in hgl {
+                    if !hosts.contains(h) {
+                        hosts.push(h.to_string());
+                    }
+                }
+            } else {
+                problems.push(format!("user {} refers to unknown host group \
+                    {}", k, hg));
+            }
+        }
+
+        if v.class != UserClass::General && !hosts.is_empty() {
+            problems.push(format!("user {} is not a general class user, but \
+                has a host list", k));
+        }
+
+        let mut date = |d: &Option<String>, what: &str| -> Option<i64> {
+            match d.as_deref().map(parse_date).transpose() {
+                Ok(d) => d,
+                Err(e) => {
+                    problems.push(format!("user {} {}: {}", k, what, e));
+                    None
+                }
+            }
+        };
+        let expires = date(&v.expires, "expires");
+        let not_before = date(&v.not_before, "not_before");
+        if let (Some(nb), Some(ex)) = (not_before, expires) {
+            if nb >= ex {
+                problems.push(format!("user {} expires before its not_before \
+                    date", k));
+            }
+        }
+
+        let mut cron = BTreeMap::new();
+        for (n, j) in v.cron.iter().flatten() {
+            if n.is_empty() || n.contains(char::is_whitespace) {
+                problems.push(format!("user {} cron job {:?} has an invalid \
+                    name", k, n));
+                continue;
+            }
+            if j.schedule.split_whitespace().count() != 5 {
+                problems.push(format!("user {} cron job {} schedule {:?} \
+                    must have five fields", k, n, j.schedule));
+                continue;
+            }
+            if j.command.trim().is_empty() || j.command.contains('\n') {
+                problems.push(format!("user {} cron job {} has an invalid \
+                    command", k, n));
+                continue;
+            }
+
+            let sched: Vec<&str> = j.schedule.split_whitespace().collect();
+            cron.insert(n.to_string(),
+                format!("{} {}", sched.join(" "), j.command.trim()));
+        }
+
+        let project = if let Some(fp) = &v.project {
+            if v.class != UserClass::Service {
+                problems.push(format!("user {} is not a service user, but has \
+                    a project", k));
+            }
+
+            let name = fp.name.as_ref()
+                .map_or_else(|| format!("user.{}", k), |s| s.to_string());
+            let comment = fp.comment.as_ref()
+                .map_or_else(|| k.to_string(), |s| s.to_string());
+            if fp.projid < 100 {
+                problems.push(format!("user {} project id {} is reserved", k,
+                    fp.projid));
+            }
+            if [&name, &comment].iter().any(|s| s.contains(':')
+                || s.contains('\n'))
+            {
+                problems.push(format!("user {} project contains invalid \
+                    characters", k));
+            }
+
+            let mut rctls = BTreeMap::new();
+            for (rk, rv) in fp.rctls.iter() {
+                if !["project.", "task.", "process."].iter()
+                    .any(|p| rk.starts_with(p))
+                {
+                    problems.push(format!("user {} rctl {} is not a project, \
+                        task or process control", k, rk));
+                    continue;
+                }
+                if rv.contains(':') || rv.contains(';') || rv.contains('\n') {
+                    problems.push(format!("user {} rctl {} has an invalid \
+                        value", k, rk));
+                    continue;
+                }
+
+                /*
+                 * A bare number is a privileged limit that denies the
+                 * operation when exceeded:
+                 */
+                let rv = if rv.parse::<u64>().is_ok() {
+                    format!("(privileged,{},deny)", rv)
+                } else {
+                    rv.to_string()
+                };
+                rctls.insert(rk.to_string(), rv);
+            }
+
+            Some(Project { name, projid: fp.projid, comment, rctls })
+        } else {
+            None
+        };
+
+        /*
+         * If no particular gecos value is specified, just use the username.
+         * The value is written into passwd(4), so it may not contain the
+         * field or line separators.
+         */
+        let gecos = v.gecos.as_ref()
+            .map_or_else(|| k.to_string(), |s| s.to_string());
+        if gecos.contains(':') || gecos.contains('\n') {
+            problems.push(format!("user {} gecos {:?} contains invalid \
+                characters", k, gecos));
+        }
+
+        users.insert(k.to_string(), User {
+            name: k.to_string(),
+            uid: v.uid,
+            class: v.class.clone(),
+            gecos,
+            group: v.group.to_string(),
+            /*
+             * If no home directory is specified, use "/home/$USER":
+             */
+            home: v.home.as_ref()
+                .map_or_else(|| format!("/home/{}", k), |s| s.to_string()),
+            /*
+             * If "home_create" does not appear in the file, we
+             * default to creating the home directory:
+             */
+            home_create: v.home_create.unwrap_or(true),
+            profiles: v.profiles.as_ref()
+                .map_or_else(|| vec![], |v| v.clone()),
+            hosts,
+            /*
+             * Users are present unless otherwise specified.  The home
+             * directory of a removed user is kept, unless we are asked to
+             * archive or destroy it.
+             */
+            state: v.state.clone().unwrap_or(State::Present),
+            home_disposal: v.home_disposal.clone()
+                .unwrap_or(HomeDisposal::Keep),
+            /*
+             * Service accounts do not get an interactive shell unless one is
+             * requested explicitly:
+             */
+            shell: v.shell.as_ref().map_or_else(|| match v.class {
+                UserClass::Service => "/usr/bin/false".to_string(),
+                UserClass::Ops | UserClass::General => "/bin/bash".to_string(),
+            }, |s| s.to_string()),
+            groups: v.groups.clone().unwrap_or_default(),
+            /*
+             * Unless the class or the user specifies a password policy, the
+             * account has no password:
+             */
+            password: v.password.clone()
+                .or_else(|| toml.classes.get(&v.class)
+                    .and_then(|fc| fc.password.clone()))
+                .unwrap_or(PasswordPolicy::NP),
+            zfs: v.zfs.clone().unwrap_or_default().merge(&toml.classes
+                .get(&v.class)
+                .and_then(|fc| fc.zfs.clone())
+                .unwrap_or_default()),
+            home_dataset: v.home_dataset.clone(),
+            auths: v.auths.clone().unwrap_or_default(),
+            roles: v.roles.clone().unwrap_or_default(),
+            /*
+             * Privilege sets are only managed if they are specified:
+             */
+            defaultpriv: v.defaultpriv.clone(),
+            limitpriv: v.limitpriv.clone(),
+            expires,
+            not_before,
+            cron,
+            project,
+        });
+    }
+
+    for (k, v) in &toml.groups {
+        let state = v.state.clone().unwrap_or(State::Present);
+        if state == State::Locked {
+            problems.push(format!("group {} cannot be locked, only present or \
+                absent", k));
+        }
+
+        groups.insert(k.to_string(), Group {
+            name: k.to_string(),
+            gid: v.gid,
+            state,
+            members: v.members.clone().unwrap_or_default(),
+        });
+    }
+
+    /*
+     * Make sure we will not remove a group that is still the primary group of
+     * a user with an account:
+     */
+    for u in users.values() {
+        if u.state == State::Absent {
+            continue;
+        }
+
+        if let Some(g) = groups.get(&u.group) {
+            if g.state == State::Absent {
+                problems.push(format!("user {} has primary group {}, which is \
+                    absent", u.name, g.name));
+            }
+        }
+    }
+
+    for (k, v) in &toml.profiles {
+        for n in std::iter::once(k).chain(v.exec.iter().map(|e| &e.command)) {
+            if n.contains(':') || n.contains(';') || n.contains('\n') {
+                problems.push(format!("profile {} contains invalid \
+                    characters", k));
+            }
+        }
+    }
+
+    (UsersConfig {
+        users,
+        groups,
+        profiles: toml.profiles.clone(),
+        /*
+         * By default, warn about accounts due to expire in the next fortnight:
+         */
+        expiry_warning_days: toml.expiry_warning_days.unwrap_or(14),
+    }, problems)
+}
+
+fn role_users(c: &Context) -> Result<()> {
+    let log = c.log();
+
+    /*
+     * Load configuration from the data directory:
+     */
+    let cfg = load(c.config()?)?;
+    let nodename = nodename(c)?;
+    let mut a = Accounts::load(c)?;
+
+    /*
+     * Check for UNIX groups...
+     */
+    info!(log, "processing groups...");
+    let mut regid: Vec<String> = Vec::new();
+    for g in cfg.groups_sorted() {
+        info!(log, "group {} gid {}", g.name, g.gid);
+
+        if g.state == State::Absent {
+            /*
+             * Groups are removed after we have processed the users, in case
+             * any departed user still has this as their primary group.
+             */
+            continue;
+        }
+
+        a.refresh(c)?;
+
+        /*
+         * Make sure the gid we want is not already in use by some other
+         * group:
+         */
+        if let Some(o) = a.group.iter()
+            .find(|o| o.gid == g.gid && o.name != g.name)
+        {
+            bail!("group {} wants gid {}, but that belongs to group {}",
+                &g.name, g.gid, &o.name);
+        }
+
+        if let Some(gid) = a.group(&g.name).map(|ge| ge.gid) {
+            info!(log, "group {} exists!", &g.name);
+
+            if gid != g.gid {
+                info!(log, "group {}: fixing gid {} -> {}...", &g.name,
+                    gid, g.gid);
+                a.run(c, &["/usr/sbin/groupmod", "-g", &g.gid.to_string(),
+                    &g.name])?;
+                regid.push(g.name.to_string());
+            }
+        } else {
+            info!(log, "group {} not found, creating...", &g.name);
+            a.run(c, &["/usr/sbin/groupadd", "-g", &g.gid.to_string(),
+                &g.name])?;
+        }
+    }
+
+    /*
+     * Install any locally defined profiles before we assign them to users:
+     */
+    info!(log, "processing profiles...");
+    fix_profiles(c, &cfg)?;
+
+    /*
+     * Projects must exist before we can make them the default project of a
+     * user:
+     */
+    info!(log, "processing projects...");
+    fix_projects(c, &cfg, &nodename)?;
+
+    /*
+     * Check for UNIX users...
+     */
+    info!(log, "processing users...");
+    let shells = valid_shells(c)?;
+    let secrets = load_secrets(c)?;
+    let today = today()?;
+    for u in cfg.users_sorted() {
+        info!(log, "user {} uid {}", &u.name, &u.uid);
+
+        a.refresh(c)?;
+
+        if u.state == State::Absent {
+            /*
+             * Departed users are removed from every host, regardless of the
+             * hosts on which they were once configured.
+             */
+            remove_user(c, &mut a, u)?;
+            continue;
+        }
+
+        if !u.applies_to(&nodename) {
+            /*
+             * The user may have been configured for this host in the past.
+             * An account that remains here must not stay usable.
+             */
+            info!(log, "user {} not configured for host {}", &u.name,
+                &nodename);
+            lock_user(c, &mut a, u)?;
+            continue;
+        }
+
+        if u.state == State::Locked {
+            lock_user(c, &mut a, u)?;
+            continue;
+        }
+
+        if !u.active(today) {
+            /*
+             * Accounts outside their access window are locked, even if the
+             * entry has not yet been removed from the configuration.
+             */
+            info!(log, "user {} is outside its access window", &u.name);
+            lock_user(c, &mut a, u)?;
+            continue;
+        }
+
+        if let Some(ex) = u.expires {
+            if ex - today <= cfg.expiry_warning_days {
+                let (y, m, d) = civil_date(ex);
+                warn!(log, "user {} expires in {} days, on {:04}-{:02}-{:02}",
+                    &u.name, ex - today, y, m, d);
+            }
+        }
+
+        check_shell(c, u, &shells)?;
+
+        /*
+         * Make sure the uid we want is not already in use by some other user:
+         */
+        if let Some(o) = a.passwd.iter()
+            .find(|o| o.uid == u.uid && o.name != u.name)
+        {
+            bail!("user {} wants uid {}, but that belongs to user {}",
+                &u.name, u.uid, &o.name);
+        }
+
+        /*
+         * If the primary group of this user changed its gid, or if we need to
+         * change the uid or primary group of the user, files in the home
+         * directory will need to be owned again by the new IDs.
+         */
+        let mut reown = regid.contains(&u.group);
+
+        if a.passwd(&u.name).is_some() {
+            info!(log, "user {} exists!", &u.name);
+
+            if fix_user(c, &mut a, u)? {
+                reown = true;
+            }
+        } else {
+            info!(log, "user {} not found, creating...", &u.name);
+            a.run(c, &["/usr/sbin/useradd",
+                "-u", &u.uid.to_string(),
+                "-g", &u.group,
+                "-d", &u.home,
+                "-s", &u.shell,
+                "-c", &u.gecos,
+                &u.name])?;
+        }
+
+        a.refresh(c)?;
+        fix_password(c, &mut a, u, &secrets)?;
+        fix_expiry(c, &mut a, u)?;
+        fix_rbac(c, &mut a, u)?;
+        fix_cron(c, u)?;
+
+        if !u.home_create {
+            /*
+             * No home directory creation required for this user.
+             */
+            continue;
+        }
+
+        match c.homedir()? {
+            HomeDir::NFS => {
+                /*
+                 * We assume, for now at least, that no action is required on a
+                 * system with automounted home directories.
+                 *
+                 * In particular, we won't reach into the NFS home directory and
+                 * (re)create any profile files or potentially damage the
+                 * contents in any way.
+                 */
+                continue;
+            }
+            HomeDir::ZFS(_) => {
+                if let Some(dsname) = home_dataset(c, &mut a, u)? {
+                    ensure_home_dataset(c, &mut a, u, &dsname)?;
+                } else {
+                    /*
+                     * There is no dataset under which this home directory
+                     * could be created (e.g., it is within the root file
+                     * system), so we will just create a directory.
+                     */
+                    info!(log, "user {}: no ZFS parent for home {}",
+                        &u.name, &u.home);
+                }
+            }
+            HomeDir::Bare => {
+                /*
+                 * On systems where neither NFS nor ZFS home directory
                  * management is in use, we will create bare directories
                  * directly under /home.
                  */
             }
-        };
+        };
+
+        let homepath = |n: &str| -> String {
+            format!("{}/{}", &u.home, n)
+        };
+
+        /*
+         * Now, the local home directory exists in one form or another.  Make
+         * sure the contents are acceptable.
+         */
+        if reown && c.check(&u.home)?.is_some() {
+            info!(log, "user {}: changing ownership of home directory...",
+                &u.name);
+            c.run(&["/usr/bin/chown", "-R",
+                &format!("{}:{}", &u.name, &u.group), &u.home])?;
+        }
+
+        c.ensure_dir(&u.home, &u.name, &u.group, 0o700)?;
+        c.ensure_dir(&homepath(".ssh"), &u.name, &u.group, 0o700)?;
+        c.ensure_dir(&homepath("bin"), &u.name, &u.group, 0o755)?;
+
+        let keys = authorized_keys(c, &u.name)?;
+        let dst = homepath(".ssh/authorized_keys");
+        if keys.is_empty() {
+            info!(log, "user {}: no SSH keys", &u.name);
+            c.ensure_removed(&dst)?;
+        } else {
+            ensure_contents(c, &keys.join(""), &dst, &u.name, &u.group,
+                0o600)?;
+        }
+
+        for (n, (src, create)) in dotfiles(c, u)? {
+            let dst = format!("{}/.{}", &u.home, n);
+
+            let create = match create {
+                DotfileCreate::Always => Create::Always,
+                DotfileCreate::IfMissing => Create::IfMissing,
+                DotfileCreate::Never => continue,
+            };
+
+            info!(log, "dotfile: {}", dst);
+            c.ensure_file(src, dst, &u.name, &u.group, 0o600, create)?;
+        }
+    }
+
+    a.refresh(c)?;
+    fix_memberships(c, &mut a, &cfg, &nodename)?;
+
+    /*
+     * Remove any groups that have departed, now that no user should still
+     * require them:
+     */
+    a.refresh(c)?;
+    for g in cfg.groups_sorted() {
+        if g.state != State::Absent {
+            continue;
+        }
+
+        if a.group(&g.name).is_some() {
+            info!(log, "group {} is absent, removing...", &g.name);
+            a.run(c, &["/usr/sbin/groupdel", &g.name])?;
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Bring supplementary group membership into line with the configuration.
+ *
+ * The supplementary groups of each user with an account on this host are
+ * exactly those listed in their "groups" entry, plus any configured group that
+ * names them in its "members" list.  Other accounts may appear in the members
+ * list of a configured group; for those accounts we manage only their
+ * membership in configured groups, and leave any other groups alone.
+ */
+fn fix_memberships(c: &Context, a: &mut Accounts, cfg: &UsersConfig,
+    nodename: &str) -> Result<()>
+{
+    let log = c.log();
+
+    info!(log, "processing group membership...");
+
+    /*
+     * Changes to the membership of one account do not affect the decisions
+     * we make for another, so we can use the snapshot as it was at the start.
+     */
+    let passwd = a.passwd.clone();
+    let groups = a.group.clone();
+
+    let managed = |n: &str| {
+        cfg.users.get(n).filter(|u| {
+            u.state != State::Absent && u.applies_to(nodename)
+        })
+    };
+    let configured = |g: &str| -> bool {
+        cfg.groups.get(g).is_some_and(|g| g.state != State::Absent)
+    };
+
+    /*
+     * Determine the set of accounts we need to consider: every managed user,
+     * every current member of a configured group, and every account named in
+     * the members list of a configured group.
+     */
+    let mut accounts: BTreeSet<String> = BTreeSet::new();
+    for u in cfg.users.values() {
+        if managed(&u.name).is_some() {
+            accounts.insert(u.name.to_string());
+        }
+    }
+    for g in cfg.groups.values() {
+        if g.state == State::Absent {
+            continue;
+        }
+        accounts.extend(g.members.iter().cloned());
+        if let Some(ge) = groups.iter().find(|ge| ge.name == g.name) {
+            accounts.extend(ge.members.iter().cloned());
+        }
+    }
+
+    for acct in accounts.iter() {
+        if !passwd.iter().any(|pw| &pw.name == acct) {
+            warn!(log, "account {} does not exist, cannot manage groups", acct);
+            continue;
+        }
+
+        let current: BTreeSet<String> = groups.iter()
+            .filter(|ge| ge.members.contains(acct))
+            .map(|ge| ge.name.to_string())
+            .collect();
+
+        let mut desired: BTreeSet<String> = if let Some(u) = managed(acct) {
+            u.groups.iter().cloned().collect()
+        } else {
+            current.iter().filter(|g| !configured(g)).cloned().collect()
+        };
+        for g in cfg.groups.values() {
+            if g.state != State::Absent && g.members.contains(acct) {
+                desired.insert(g.name.to_string());
+            }
+        }
+
+        /*
+         * Groups that are not configured here may be created by some other
+         * role (e.g., "www" comes with the web server) and might not exist
+         * on this host:
+         */
+        desired.retain(|g| {
+            if groups.iter().any(|ge| &ge.name == g) {
+                true
+            } else {
+                warn!(log, "account {}: group {} does not exist, skipping",
+                    acct, g);
+                false
+            }
+        });
+
+        if desired == current {
+            continue;
+        }
+
+        let list = desired.iter().cloned().collect::<Vec<_>>().join(",");
+        info!(log, "account {}: fixing supplementary groups: {:?} -> {:?}",
+            acct, current, desired);
+        a.run(c, &["/usr/sbin/usermod", "-G", &list, acct])?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Determine the set of dotfiles to install for a user.  Files are layered:
+ * first the common set in "dotfiles", then "dotfiles/<class>", then
+ * "dotfiles/<user>".  A file in a later layer replaces one of the same name
+ * from an earlier layer, and the manifest in each layer may change how a file
+ * of a particular name from that layer or an earlier one is installed.
+ */
+fn dotfiles(c: &Context, u: &User)
+    -> Result<BTreeMap<String, (PathBuf, DotfileCreate)>>
+{
+    let mut out: BTreeMap<String, (PathBuf, DotfileCreate)> = BTreeMap::new();
+
+    let layers = [
+        "dotfiles".to_string(),
+        format!("dotfiles/{}", u.class.name()),
+        format!("dotfiles/{}", &u.name),
+    ];
+
+    for layer in layers.iter() {
+        if let Some(files) = c.files_maybe(layer)? {
+            for src in files.iter().filter(|f| f.is_file()) {
+                let n = src.file_name().unwrap().to_str()
+                    .expect("dotfile name");
+                if n == "manifest.toml" {
+                    continue;
+                }
+
+                /*
+                 * A file supplied by this layer is installed as usual, even
+                 * if an earlier manifest said otherwise for the file it
+                 * replaces, unless the manifest for this layer says so.
+                 */
+                out.insert(n.to_string(),
+                    (src.clone(), DotfileCreate::IfMissing));
+            }
+        }
+
+        if let Some(f) = c.file_maybe(format!("{}/manifest.toml", layer))? {
+            let manifest: FileManifest =
+                toml::from_str(&std::fs::read_to_string(&f)?)
+                .map_err(|e| anyhow!("parsing {}: {}", f.display(), e))?;
+
+            for (n, fd) in manifest {
+                if let Some(ent) = out.get_mut(&n) {
+                    ent.1 = fd.create;
+                }
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/*
+ * Assemble the authorized_keys(5) contents for a user from the key sources in
+ * the files directory: keys are read verbatim from "ssh_keys/<user>", and the
+ * structured "ssh_keys/<user>.toml" contributes any keys that have not yet
+ * expired.
+ */
+fn authorized_keys(c: &Context, name: &str) -> Result<Vec<String>> {
+    let log = c.log();
+    let mut out = Vec::new();
+
+    if let Some(f) = c.file_maybe(format!("ssh_keys/{}", name))? {
+        for l in c.read_lines(&f)?.expect("should still exist") {
+            if !l.trim().is_empty() && !l.trim().starts_with('#') {
+                out.push(format!("{}\n", l.trim()));
+            }
+        }
+    }
+
+    if let Some(f) = c.file_maybe(format!("ssh_keys/{}.toml", name))? {
+        let fk: FileKeys = toml::from_str(&std::fs::read_to_string(&f)?)
+            .map_err(|e| anyhow!("parsing {}: {}", f.display(), e))?;
+        let today = today()?;
+
+        for k in fk.keys.iter() {
+            let problems = k.problems();
+            if !problems.is_empty() {
+                warn!(log, "user {}: {}, skipping", name,
+                    problems.join("; "));
+                continue;
+            }
+
+            if let Some(expires) = &k.expires {
+                if parse_date(expires)? <= today {
+                    info!(log, "user {}: key {} expired on {}, skipping",
+                        name, k.comment.as_deref().unwrap_or("-"), expires);
+                    continue;
+                }
+            }
+
+            out.push(k.line());
+        }
+    }
+
+    Ok(out)
+}
+
+/*
+ * Shells that do not allow an interactive login, and which are thus never
+ * listed in shells(4), but which are appropriate for service accounts.
+ */
+const NOLOGIN_SHELLS: &[&str] = &[
+    "/usr/bin/false",
+    "/bin/false",
+];
+
+/*
+ * Determine the list of valid login shells on this system.  If shells(4) does
+ * not exist, we use the same default list as getusershell(3C).
+ */
+fn valid_shells(c: &Context) -> Result<Vec<String>> {
+    Ok(if let Some(lines) = c.read_lines("/etc/shells")? {
+        lines.iter()
+            .map(|l| l.trim())
+            .filter(|l| l.starts_with('/'))
+            .map(|l| l.to_string())
+            .collect()
+    } else {
+        [
+            "/bin/bash", "/bin/csh", "/bin/jsh", "/bin/ksh", "/bin/ksh93",
+            "/bin/pfcsh", "/bin/pfksh", "/bin/pfsh", "/bin/sh", "/bin/tcsh",
+            "/bin/zsh", "/sbin/jsh", "/sbin/pfsh", "/sbin/sh",
+            "/usr/bin/bash", "/usr/bin/csh", "/usr/bin/jsh", "/usr/bin/ksh",
+            "/usr/bin/ksh93", "/usr/bin/pfcsh", "/usr/bin/pfksh",
+            "/usr/bin/pfsh", "/usr/bin/sh", "/usr/bin/tcsh", "/usr/bin/zsh",
+            "/usr/sbin/jsh", "/usr/sbin/pfsh", "/usr/sbin/sh",
+            "/usr/xpg4/bin/sh",
+        ].iter().map(|s| s.to_string()).collect()
+    })
+}
+
+/*
+ * Make sure the configured shell for a user exists and is acceptable, before
+ * we try to use it in useradd(1M) or usermod(1M).
+ */
+fn check_shell(c: &Context, u: &User, shells: &[String]) -> Result<()> {
+    if !NOLOGIN_SHELLS.contains(&u.shell.as_str())
+        && !shells.contains(&u.shell)
+    {
+        bail!("user {} shell {} is not a valid login shell on this system",
+            &u.name, &u.shell);
+    }
+
+    match c.check(&u.shell)? {
+        Some(fi) if fi.is_user_executable() => Ok(()),
+        Some(_) => bail!("user {} shell {} is not executable",
+            &u.name, &u.shell),
+        None => bail!("user {} shell {} does not exist", &u.name, &u.shell),
+    }
+}
+
+/*
+ * Compare the passwd(4) entry for an existing user with the configuration, and
+ * use usermod(1M) to correct any attributes that differ.  Returns true if the
+ * uid or primary group of the user was changed.
+ */
+fn fix_user(c: &Context, a: &mut Accounts, u: &User) -> Result<bool> {
+    let log = c.log();
+
+    let pw = a.passwd(&u.name).expect("user exists").clone();
+    let gid = if let Some(ge) = a.group(&u.group) {
+        ge.gid
+    } else {
+        bail!("user {} primary group {} does not exist", &u.name, &u.group);
+    };
+
+    let uid = u.uid.to_string();
+    let mut args: Vec<&str> = Vec::new();
+    let mut reown = false;
+
+    if pw.uid != u.uid {
+        info!(log, "user {}: uid {} -> {}", &u.name, pw.uid, u.uid);
+        args.push("-u");
+        args.push(&uid);
+        reown = true;
+    }
+    if pw.gid != gid {
+        info!(log, "user {}: gid {} -> {}", &u.name, pw.gid, gid);
+        args.push("-g");
+        args.push(&u.group);
+        reown = true;
+    }
+    if pw.gecos != u.gecos {
+        info!(log, "user {}: gecos {:?} -> {:?}", &u.name, pw.gecos, u.gecos);
+        args.push("-c");
+        args.push(&u.gecos);
+    }
+    if pw.home != u.home {
+        info!(log, "user {}: home {} -> {}", &u.name, pw.home, u.home);
+        args.push("-d");
+        args.push(&u.home);
+    }
+    if pw.shell != u.shell {
+        info!(log, "user {}: shell {} -> {}", &u.name, pw.shell, u.shell);
+        args.push("-s");
+        args.push(&u.shell);
+    }
+
+    if !args.is_empty() {
+        info!(log, "user {}: fixing attributes...", &u.name);
+        let mut cmd = vec!["/usr/sbin/usermod"];
+        cmd.extend(args);
+        cmd.push(&u.name);
+        a.run(c, &cmd)?;
+    }
+
+    Ok(reown)
+}
+
+fn attr_list(attrs: &BTreeMap<String, String>, key: &str) -> Vec<String> {
+    attrs.get(key).map_or_else(Vec::new, |v| {
+        v.split(',').filter(|v| !v.is_empty()).map(|v| v.to_string())
+            .collect()
+    })
+}
+
+/*
+ * Make sure the RBAC attributes (profiles, authorisations, roles, and
+ * privilege sets) of a user are as configured.
+ *
+ * We take extra care to avoid running "usermod" here if we do not need to
+ * adjust any attributes.  This tool appears to alter the database whether or
+ * not it needed to do so, which causes nscd to drop its cache on the floor for
+ * two seconds (to debounce file modifications) and holds up each turn of the
+ * loop.
+ */
+fn fix_rbac(c: &Context, a: &mut Accounts, u: &User) -> Result<()> {
+    let log = c.log();
+
+    let attrs = a.attrs(&u.name);
+    info!(log, "user attributes: {:#?}", attrs);
+
+    let profiles = u.profiles.join(",");
+    let auths = u.auths.join(",");
+    let roles = u.roles.join(",");
+    let defaultpriv = u.defaultpriv.as_ref()
+        .map(|p| format!("defaultpriv={}", p.join(",")));
+    let limitpriv = u.limitpriv.as_ref()
+        .map(|p| format!("limitpriv={}", p.join(",")));
+
+    let mut args: Vec<&str> = Vec::new();
+    if attr_list(&attrs, "profiles") != u.profiles {
+        info!(log, "user {}: fixing profiles...", &u.name);
+        args.push("-P");
+        args.push(&profiles);
+    }
+    if attr_list(&attrs, "auths") != u.auths {
+        info!(log, "user {}: fixing authorisations...", &u.name);
+        args.push("-A");
+        args.push(&auths);
+    }
+    if attr_list(&attrs, "roles") != u.roles {
+        info!(log, "user {}: fixing roles...", &u.name);
+        args.push("-R");
+        args.push(&roles);
+    }
+    let privs = [
+        ("defaultpriv", &u.defaultpriv, &defaultpriv),
+        ("limitpriv", &u.limitpriv, &limitpriv),
+    ];
+    for (key, want, arg) in privs.iter() {
+        if let (Some(want), Some(arg)) = (want, arg) {
+            if &attr_list(&attrs, key) != want {
+                info!(log, "user {}: fixing {}...", &u.name, key);
+                args.push("-K");
+                args.push(arg);
+            }
+        }
+    }
+    let project = u.project.as_ref()
+        .map(|p| (p.name.as_str(), format!("project={}", p.name)));
+    if let Some((name, arg)) = &project {
+        if attrs.get("project").map(|s| s.as_str()) != Some(*name) {
+            info!(log, "user {}: fixing default project...", &u.name);
+            args.push("-K");
+            args.push(arg);
+        }
+    }
+
+    if !args.is_empty() {
+        let mut cmd = vec!["/usr/sbin/usermod"];
+        cmd.extend(args);
+        cmd.push(&u.name);
+        a.run(c, &cmd)?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Write the locally defined RBAC profiles into our own files in the
+ * prof_attr.d and exec_attr.d directories, which are read in addition to the
+ * system databases.
+ */
+fn fix_profiles(c: &Context, cfg: &UsersConfig) -> Result<()> {
+    let log = c.log();
+
+    let esc = |s: &str| -> String {
+        s.replace('\\', "\\\\").replace(':', "\\:").replace(';', "\\;")
+    };
+
+    let mut prof = String::new();
+    let mut exec = String::new();
+    for (name, p) in cfg.profiles.iter() {
+        info!(log, "profile {}", name);
+
+        let mut attrs = Vec::new();
+        if !p.auths.is_empty() {
+            attrs.push(format!("auths={}", esc(&p.auths.join(","))));
+        }
+        if !p.profiles.is_empty() {
+            attrs.push(format!("profiles={}", esc(&p.profiles.join(","))));
+        }
+        prof.push_str(&format!("{}:::{}:{}\n", name,
+            esc(p.desc.as_deref().unwrap_or("")), attrs.join(";")));
+
+        for e in p.exec.iter() {
+            let attrs: Vec<String> = e.attrs.iter()
+                .map(|(k, v)| format!("{}={}", k, esc(v)))
+                .collect();
+            exec.push_str(&format!("{}:solaris:cmd:::{}:{}\n", name,
+                e.command, attrs.join(";")));
+        }
+    }
+
+    for (dir, contents) in &[
+        ("/etc/security/prof_attr.d", prof),
+        ("/etc/security/exec_attr.d", exec),
+    ] {
+        let f = format!("{}/confomat", dir);
+        if contents.is_empty() {
+            c.ensure_removed(&f)?;
+        } else {
+            ensure_contents(c, contents, &f, ROOT, "sys", 0o644)?;
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Maintain the project(4) entries for service users.  We own the projects
+ * named in the configuration and the conventional "user.<name>" project of
+ * every configured user, removing any of those that are no longer wanted;
+ * other entries in the file are left as they are.
+ */
+fn fix_projects(c: &Context, cfg: &UsersConfig, nodename: &str)
+    -> Result<()>
+{
+    let log = c.log();
+
+    let mut owned: BTreeSet<String> = BTreeSet::new();
+    let mut want: BTreeMap<String, (i64, String)> = BTreeMap::new();
+    for u in cfg.users_sorted() {
+        owned.insert(format!("user.{}", u.name));
+
+        if let Some(p) = &u.project {
+            owned.insert(p.name.to_string());
+
+            if u.state != State::Absent && u.applies_to(nodename) {
+                if let Some((o, _)) = want.iter()
+                    .find(|(_, (projid, _))| *projid == p.projid)
+                {
+                    bail!("projects {} and {} have the same id {}", o,
+                        p.name, p.projid);
+                }
+                want.insert(p.name.to_string(),
+                    (p.projid, p.line(&u.name)));
+            }
+        }
+    }
+
+    let lines = c.read_lines("/etc/project")?.unwrap_or_default();
+    let mut out = String::new();
+    let mut done: BTreeSet<String> = BTreeSet::new();
+    for l in lines.iter() {
+        let t: Vec<&str> = l.split(':').collect();
+        if l.trim().is_empty() || l.starts_with('#') || t.len() != 6 {
+            out += &format!("{}\n", l);
+            continue;
+        }
+
+        if let Some((projid, line)) = want.get(t[0]) {
+            if l != line {
+                info!(log, "project {}: fixing entry (id {})...", t[0],
+                    projid);
+            }
+            out += &format!("{}\n", line);
+            done.insert(t[0].to_string());
+        } else if owned.contains(t[0]) {
+            info!(log, "project {} is not configured, removing...", t[0]);
+        } else {
+            if let Some((n, _)) = want.iter()
+                .find(|(_, (projid, _))| projid.to_string() == t[1])
+            {
+                bail!("project {} wants id {}, but that belongs to project {}",
+                    n, t[1], t[0]);
+            }
+            out += &format!("{}\n", l);
+        }
+    }
+    for (name, (_, line)) in want.iter() {
+        if !done.contains(name) {
+            info!(log, "project {} not found, creating...", name);
+            out += &format!("{}\n", line);
+        }
+    }
+
+    ensure_contents(c, &out, "/etc/project", ROOT, "sys", 0o644)?;
+
+    Ok(())
+}
+
+/*
+ * Determine the name of the ZFS dataset for the home directory of this user,
+ * if it has one on this system.  A dataset may be specified explicitly in the
+ * configuration; otherwise, we look for the mounted file system with the
+ * longest mountpoint that contains the home directory, and use a descendant
+ * of that dataset with the same relative path.
+ */
+fn home_dataset(c: &Context, a: &mut Accounts, u: &User)
+    -> Result<Option<String>>
+{
+    if !u.home_create {
+        return Ok(None);
+    }
+
+    let dataset = if let HomeDir::ZFS(dataset) = c.homedir()? {
+        dataset
+    } else {
+        return Ok(None);
+    };
+
+    if let Some(ds) = &u.home_dataset {
+        return Ok(Some(ds.to_string()));
+    }
+
+    /*
+     * The simplest layout is rpool/home/$USER => /home/$USER:
+     */
+    if u.home == format!("/home/{}", &u.name) {
+        return Ok(Some(format!("{}/{}", dataset, &u.name)));
+    }
+
+    let home = Path::new(&u.home);
+    let mut best: Option<(String, PathBuf)> = None;
+    for (name, mp) in a.datasets()? {
+        /*
+         * Never create home directories within the root file system or within
+         * a boot environment:
+         */
+        let mp = PathBuf::from(mp);
+        if mp == Path::new("/") || name.split('/').nth(1) == Some("ROOT") {
+            continue;
+        }
+
+        if home.starts_with(&mp) && best.as_ref().is_none_or(
+            |(_, bmp)| mp.components().count() > bmp.components().count())
+        {
+            best = Some((name.to_string(), mp));
+        }
+    }
+
+    /*
+     * A home directory that is itself a mountpoint belongs to a dataset that
+     * may be shared with other users, so it must be named explicitly with
+     * "home_dataset" if it is to be managed:
+     */
+    Ok(best.and_then(|(name, mp)| {
+        let rel = home.strip_prefix(&mp).unwrap();
+        if rel.as_os_str().is_empty() {
+            None
+        } else {
+            Some(format!("{}/{}", name, rel.display()))
+        }
+    }))
+}
+
+/*
+ * Create the home dataset for a user, along with any intermediate datasets,
+ * and make sure its properties and mountpoint are as we expect.
+ */
+fn ensure_home_dataset(c: &Context, a: &mut Accounts, u: &User, dsname: &str)
+    -> Result<()>
+{
+    /*
+     * Intermediate datasets inherit their mountpoint from their parent.  The
+     * first component is the pool, which must already exist.
+     */
+    let comps: Vec<&str> = dsname.split('/').collect();
+    for i in 2..comps.len() {
+        let parent = comps[..i].join("/");
+        if a.datasets()?.iter().any(|(name, _)| name == &parent) {
+            continue;
+        }
+        if !dataset_exists(&parent)? {
+            c.ensure_dataset(&parent, &[])?;
+        }
+    }
+
+    let mut props = u.zfs.clone();
+    if u.home_dataset.is_some() {
+        /*
+         * An explicitly named dataset may not be where the home directory
+         * would otherwise be mounted.
+         */
+        props.insert("mountpoint".to_string(), u.home.to_string());
+    }
+
+    let create: Vec<String> = props.iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    let create: Vec<&str> = create.iter()
+        .map(|p| p.as_str())
+        .collect();
+    c.ensure_dataset(dsname, &create)?;
+
+    /*
+     * The dataset may have existed already, so make sure the properties are
+     * set as we expect:
+     */
+    ensure_dataset_props(c, dsname, &props)
+}
+
+/*
+ * Remove the authorised SSH keys for a user whose account is being locked or
+ * removed.  Shared NFS home directories are never modified.
+ */
+fn strip_keys(c: &Context, u: &User) -> Result<()> {
+    if !u.home_create {
+        return Ok(());
+    }
+
+    match c.homedir()? {
+        HomeDir::NFS => (),
+        HomeDir::ZFS(_) | HomeDir::Bare => {
+            c.ensure_removed(format!("{}/.ssh/authorized_keys", &u.home))?;
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Make sure the password for a user matches the policy for the account.  We
+ * only modify the shadow file if the password actually needs to change.
+ */
+fn fix_password(c: &Context, a: &mut Accounts, u: &User,
+    secrets: &SecretsToml) -> Result<()>
+{
+    let log = c.log();
+
+    let current = a.shadow_password(&u.name)?.to_string();
+
+    let policy = match (&u.password, secrets.password_hash.get(&u.name)) {
+        (PasswordPolicy::Hash, None) => {
+            warn!(log, "user {}: no password hash in secrets file, \
+                locking instead", &u.name);
+            &PasswordPolicy::LK
+        }
+        (p, _) => p,
+    };
+
+    match policy {
+        PasswordPolicy::NP => {
+            if current != "NP" {
+                info!(log, "user {}: setting nopassword...", &u.name);
+                a.run(c, &["/bin/passwd", "-N", &u.name])?;
+            }
+        }
+        PasswordPolicy::LK => {
+            if !current.starts_with("*LK*") {
+                info!(log, "user {}: locking password...", &u.name);
+                a.run(c, &["/bin/passwd", "-l", &u.name])?;
+            }
+        }
+        PasswordPolicy::Hash => {
+            let hash = &secrets.password_hash[&u.name];
+            if &current != hash {
+                info!(log, "user {}: setting password hash...", &u.name);
+                a.stale = true;
+                write_shadow_password(c, &u.name, hash)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+const CRON_STATE: &str = "/var/confomat/cron";
+const CRONTABS: &str = "/var/spool/cron/crontabs";
+
+/*
+ * Install the scheduled jobs for a user through ensure_cron(), then remove any
+ * jobs we installed on a previous run that are no longer configured.  The jobs
+ * we manage are recorded in a state file for each user, so that entries added
+ * to the crontab by other means are left alone.
+ */
+fn fix_cron(c: &Context, u: &User) -> Result<()> {
+    for (name, line) in u.cron.iter() {
+        c.ensure_cron(&u.name, name, line)?;
+    }
+
+    /*
+     * A job that is gone from the configuration, or whose schedule or command
+     * has changed, leaves behind the line we installed for it before.
+     */
+    let stale: Vec<String> = cron_state(c, &u.name)?.into_values()
+        .filter(|l| !u.cron.values().any(|w| w == l))
+        .collect();
+    remove_cron_lines(c, &u.name, &stale)?;
+
+    /*
+     * Only record the new state once the stale lines are gone, so that a
+     * failure is tried again on the next run.
+     */
+    let state = Path::new(CRON_STATE).join(&u.name);
+    if u.cron.is_empty() {
+        c.ensure_removed(&state)?;
+    } else {
+        let mut out = String::new();
+        for (name, line) in u.cron.iter() {
+            out += &format!("{}\t{}\n", name, line);
+        }
+
+        c.ensure_dir(CRON_STATE, ROOT, "sys", 0o700)?;
+        ensure_contents(c, &out, &state, ROOT, "sys", 0o600)?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Read the jobs we last installed for a user from the state file.
+ */
+fn cron_state(c: &Context, user: &str) -> Result<BTreeMap<String, String>> {
+    let state = Path::new(CRON_STATE).join(user);
+
+    Ok(c.read_lines(&state)?.unwrap_or_default().iter()
+        .filter_map(|l| l.split_once('\t'))
+        .map(|(n, l)| (n.to_string(), l.to_string()))
+        .collect())
+}
+
+/*
+ * Remove every job we installed for a user, and forget about them.
+ */
+fn remove_cron(c: &Context, user: &str) -> Result<()> {
+    let stale: Vec<String> = cron_state(c, user)?.into_values().collect();
+    remove_cron_lines(c, user, &stale)?;
+    c.ensure_removed(Path::new(CRON_STATE).join(user))?;
+
+    Ok(())
+}
+
+/*
+ * Remove the specified lines from the crontab of a user.
+ */
+fn remove_cron_lines(c: &Context, user: &str, stale: &[String])
+    -> Result<()>
+{
+    let log = c.log();
+
+    if stale.is_empty() || !c.exists_file(Path::new(CRONTABS).join(user))? {
+        return Ok(());
+    }
+
+    let current = c.run(&["/usr/bin/crontab", "-l", user])?;
+    let lines: Vec<&str> = current.lines()
+        .filter(|l| !stale.iter().any(|s| s == l.trim()))
+        .collect();
+    let removed = current.lines().count() - lines.len();
+    if removed == 0 {
+        return Ok(());
+    }
+
+    info!(log, "user {}: removing {} cron job(s)...", user, removed);
+    let mut contents = String::new();
+    for l in lines {
+        contents += &format!("{}\n", l);
+    }
+
+    /*
+     * The crontab must be replaced through crontab(1), which tells cron to
+     * read it again without disturbing any jobs that are running.  We cannot
+     * use "su - <user> -c crontab", as service accounts have /usr/bin/false
+     * as their shell.  The only mode in which root may name another user is
+     * the edit mode, so the "editor" we provide copies the new contents over
+     * the file being edited.
+     */
+    let tmp = PathBuf::from(format!("/var/run/.confomat.crontab.{}",
+        std::process::id()));
+    write_private(&tmp, &contents)?;
+    let res = c.run(&["/usr/bin/env",
+        &format!("EDITOR=/bin/cp {}", tmp.display()),
+        "/usr/bin/crontab", "-e", user]);
+    std::fs::remove_file(&tmp)?;
+    res?;
+
+    Ok(())
+}
+
+/*
+ * Make sure the account expiry date in the shadow(4) file matches the
+ * configuration, clearing it if the user no longer has one.
+ */
+fn fix_expiry(c: &Context, a: &mut Accounts, u: &User) -> Result<()> {
+    let log = c.log();
+
+    if a.shadow_expire(&u.name)? == u.expires {
+        return Ok(());
+    }
+
+    /*
+     * The expiry date is interpreted by getdate(3C) using the formats in
+     * /etc/datemsk, which by default include "%m/%d/%y".
+     */
+    let arg = u.expires.map_or_else(String::new, |ex| {
+        let (y, m, d) = civil_date(ex);
+        format!("{:02}/{:02}/{:02}", m, d, y % 100)
+    });
+    info!(log, "user {}: setting expiry date {:?}...", &u.name, arg);
+    a.run(c, &["/usr/sbin/usermod", "-e", &arg, &u.name])?;
+
+    Ok(())
+}
+
+fn lock_user(c: &Context, a: &mut Accounts, u: &User) -> Result<()> {
+    let log = c.log();
+
+    if a.passwd(&u.name).is_none() {
+        info!(log, "user {} has no account here", &u.name);
+        return Ok(());
+    }
+
+    if !a.shadow_password(&u.name)?.starts_with("*LK*") {
+        info!(log, "user {}: locking account...", &u.name);
+        a.run(c, &["/bin/passwd", "-l", &u.name])?;
+    }
+
+    strip_keys(c, u)?;
+
+    /*
+     * Remove any profiles, authorisations or roles that would allow the user
+     * to do more than a regular user.
+     */
+    let attrs = a.attrs(&u.name);
+    let mut args: Vec<&str> = Vec::new();
+    for (key, arg) in &[("profiles", "-P"), ("auths", "-A"), ("roles", "-R")] {
+        if !attr_list(&attrs, key).is_empty() {
+            info!(log, "user {}: removing {}...", &u.name, key);
+            args.push(*arg);
+            args.push("");
+        }
+    }
+    if !args.is_empty() {
+        let mut cmd = vec!["/usr/sbin/usermod"];
+        cmd.extend(args);
+        cmd.push(&u.name);
+        a.run(c, &cmd)?;
+    }
+
+    Ok(())
+}
+
+fn remove_user(c: &Context, a: &mut Accounts, u: &User) -> Result<()> {
+    let log = c.log();
+
+    if a.passwd(&u.name).is_some() {
+        info!(log, "user {} is absent, removing...", &u.name);
+        remove_cron(c, &u.name)?;
+        a.run(c, &["/usr/sbin/userdel", &u.name])?;
+    }
+
+    let dsname = match home_dataset(c, a, u)? {
+        Some(dsname) if dataset_exists(&dsname)? => dsname,
+        _ => {
+            /*
+             * The home directory, if any, is not a dataset we manage.  Just
+             * make sure nobody can log in with the keys that remain there.
+             */
+            return strip_keys(c, u);
+        }
+    };
+
+    match u.home_disposal {
+        HomeDisposal::Keep => {
+            info!(log, "user {}: keeping home dataset {}", &u.name, &dsname);
+            strip_keys(c, u)?;
+        }
+        HomeDisposal::Archive | HomeDisposal::Destroy
+            if u.home_dataset.as_deref() != Some(dsname.as_str())
+                && !dsname.ends_with(&format!("/{}", &u.name)) =>
+        {
+            /*
+             * Only a dataset named for the user, or one configured
+             * explicitly, is surely theirs alone.
+             */
+            warn!(log, "user {}: home dataset {} is not named for the user, \
+                so it will not be archived or destroyed", &u.name, &dsname);
+            strip_keys(c, u)?;
+        }
+        HomeDisposal::Archive => {
+            /*
+             * Move the home dataset under an unmounted archive dataset, where
+             * it remains available to an administrator but no longer appears
+             * under /home.
+             */
+            let parent = Path::new(&dsname).parent().unwrap();
+            let archive = format!("{}/_archive", parent.display());
+            c.ensure_dataset(&archive, &["mountpoint=none", "canmount=off"])?;
+
+            /*
+             * A user may be removed more than once, so each archived home is
+             * named for the date on which it was archived.
+             */
+            let (y, m, d) = civil_date(today()?);
+            let base = format!("{}/{}-{:04}{:02}{:02}", archive, &u.name,
+                y, m, d);
+            let mut target = base.clone();
+            let mut n = 1;
+            while dataset_exists(&target)? {
+                n += 1;
+                target = format!("{}.{}", base, n);
+            }
 
-        let homepath = |n: &str| -> String {
-            format!("{}/{}", &u.home, n)
+            info!(log, "user {}: archiving home dataset {} to {}",
+                &u.name, &dsname, &target);
+            c.run(&["/sbin/zfs", "rename", &dsname, &target])?;
+
+            /*
+             * A home with an explicit mountpoint would otherwise remain
+             * mounted where it was.
+             */
+            c.run(&["/sbin/zfs", "set", "canmount=off", &target])?;
+            c.run(&["/sbin/zfs", "set", "mountpoint=none", &target])?;
+        }
+        HomeDisposal::Destroy => {
+            info!(log, "user {}: destroying home dataset {}",
+                &u.name, &dsname);
+            c.run(&["/sbin/zfs", "destroy", "-r", &dsname])?;
+        }
+    }
+
+    Ok(())
+}
+
+pub enum ExportFormat {
+    Passwd,
+    Group,
+    Ldif(String),
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportUser {
+    name: String,
+    uid: i64,
+    gid: i64,
+    group: String,
+    gecos: String,
+    home: String,
+    shell: String,
+    groups: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportGroup {
+    name: String,
+    gid: i64,
+    members: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Export {
+    users: Vec<ExportUser>,
+    groups: Vec<ExportGroup>,
+}
+
+/*
+ * Values in LDIF must be base64 encoded if they are not "safe" strings, such
+ * as a gecos field with non-ASCII characters.
+ */
+fn ldif_attr(k: &str, v: &str) -> String {
+    let safe = v.bytes().all(|b| (0x20..0x7f).contains(&b))
+        && !v.starts_with(' ') && !v.starts_with(':') && !v.starts_with('<')
+        && !v.ends_with(' ');
+    if safe {
+        return format!("{}: {}\n", k, v);
+    }
+
+    const B64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+        abcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for ch in v.as_bytes().chunks(3) {
+        let n = (ch[0] as u32) << 16
+            | (*ch.get(1).unwrap_or(&0) as u32) << 8
+            | *ch.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= ch.len() {
+                out.push(B64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    format!("{}:: {}\n", k, out)
+}
+
+/*
+ * Produce the effective users and groups from the configuration in the
+ * requested format.  The records are resolved by load(), exactly as they are
+ * for the role, so that defaults for gecos and home are the same everywhere.
+ * Departed users and groups are not included.  If a host is specified, only
+ * users that would have an account on that host are included.
+ */
+pub fn export(root: &Path, format: &ExportFormat, host: Option<&str>)
+    -> Result<String>
+{
+    let cfg = load(read_config(root, "users")?)?;
+
+    let users: Vec<&User> = cfg.users_sorted().into_iter()
+        .filter(|u| u.state != State::Absent)
+        .filter(|u| host.is_none_or(|h| u.applies_to(h)))
+        .collect();
+    let groups: Vec<&Group> = cfg.groups_sorted().into_iter()
+        .filter(|g| g.state != State::Absent)
+        .collect();
+
+    let mut out = Export { users: Vec::new(), groups: Vec::new() };
+
+    for u in users.iter() {
+        let gid = if let Some(g) = groups.iter().find(|g| g.name == u.group) {
+            g.gid
+        } else {
+            bail!("user {} primary group {} is not configured", u.name,
+                u.group);
         };
 
+        out.users.push(ExportUser {
+            name: u.name.to_string(),
+            uid: u.uid,
+            gid,
+            group: u.group.to_string(),
+            gecos: u.gecos.to_string(),
+            home: u.home.to_string(),
+            shell: u.shell.to_string(),
+            groups: u.groups.clone(),
+        });
+    }
+
+    for g in groups.iter() {
         /*
-         * Now, the local home directory exists in one form or another.  Make
-         * sure the contents are acceptable.
+         * When exporting for a particular host, members without an account
+         * on that host are left out, as their user entries are.
          */
-        c.ensure_dir(&u.home, &u.name, &u.group, 0o700)?;
-        c.ensure_dir(&homepath(".ssh"), &u.name, &u.group, 0o700)?;
-        c.ensure_dir(&homepath("bin"), &u.name, &u.group, 0o755)?;
+        let mut members: BTreeSet<String> = g.members.iter()
+            .filter(|m| host.is_none() || users.iter().any(|u| &u.name == *m))
+            .cloned()
+            .collect();
+        for u in users.iter() {
+            if u.groups.contains(&g.name) {
+                members.insert(u.name.to_string());
+            }
+        }
+
+        out.groups.push(ExportGroup {
+            name: g.name.to_string(),
+            gid: g.gid,
+            members: members.into_iter().collect(),
+        });
+    }
 
-        let sshkeys = c.file_maybe(format!("ssh_keys/{}", &u.name))?;
-        if let Some(sshkeys) = sshkeys {
-            let dst = homepath(".ssh/authorized_keys");
-            c.ensure_file(&sshkeys, &dst, &u.name, &u.group, 0o600,
-                Create::Always)?;
+    let mut s = String::new();
+    match format {
+        ExportFormat::Passwd => {
+            for u in out.users.iter() {
+                s.push_str(&format!("{}:x:{}:{}:{}:{}:{}\n", u.name, u.uid,
+                    u.gid, u.gecos, u.home, u.shell));
+            }
+        }
+        ExportFormat::Group => {
+            for g in out.groups.iter() {
+                s.push_str(&format!("{}:x:{}:{}\n", g.name, g.gid,
+                    g.members.join(",")));
+            }
+        }
+        ExportFormat::Ldif(base) => {
+            for g in out.groups.iter() {
+                s.push_str(&format!("dn: cn={},ou=Groups,{}\n", g.name, base));
+                s.push_str("objectClass: top\n");
+                s.push_str("objectClass: posixGroup\n");
+                s.push_str(&ldif_attr("cn", &g.name));
+                s.push_str(&format!("gidNumber: {}\n", g.gid));
+                for m in g.members.iter() {
+                    s.push_str(&ldif_attr("memberUid", m));
+                }
+                s.push('\n');
+            }
+            for u in out.users.iter() {
+                s.push_str(&format!("dn: uid={},ou=People,{}\n", u.name, base));
+                s.push_str("objectClass: top\n");
+                s.push_str("objectClass: account\n");
+                s.push_str("objectClass: posixAccount\n");
+                s.push_str(&ldif_attr("uid", &u.name));
+                s.push_str(&ldif_attr("cn", &u.gecos));
+                s.push_str(&format!("uidNumber: {}\n", u.uid));
+                s.push_str(&format!("gidNumber: {}\n", u.gid));
+                s.push_str(&ldif_attr("gecos", &u.gecos));
+                s.push_str(&ldif_attr("homeDirectory", &u.home));
+                s.push_str(&ldif_attr("loginShell", &u.shell));
+                s.push('\n');
+            }
         }
+        ExportFormat::Json => {
+            s = serde_json::to_string_pretty(&out)?;
+            s.push('\n');
+        }
+    }
 
-        let dotfiles = c.files("dotfiles")?;
-        for src in &dotfiles {
-            let n = src.file_name().unwrap().to_str().expect("dotfile name");
-            let dst = format!("{}/.{}", &u.home, n);
+    Ok(s)
+}
 
-            info!(log, "dotfile: {}", dst);
-            c.ensure_file(src, dst, &u.name, &u.group, 0o600,
-                Create::IfMissing)?;
+/*
+ * Validate the users configuration and files without a live system, reporting
+ * the mistakes that would otherwise only be found part way through a run.
+ */
+pub fn check(root: &Path) -> Result<Vec<String>> {
+    let (cfg, mut problems) = load_checked(read_config(root, "users")?);
+    let files = root.join("files").join("users");
+
+    for u in cfg.users.values() {
+        if !cfg.groups.contains_key(&u.group) {
+            problems.push(format!("user {} primary group {} is not in \
+                [groups]", u.name, u.group));
+        }
+
+        for o in cfg.users.values() {
+            if o.name < u.name && o.uid == u.uid {
+                problems.push(format!("users {} and {} have the same uid {}",
+                    o.name, u.name, u.uid));
+            }
+
+            if let (Some(p), Some(op)) = (&u.project, &o.project) {
+                if o.name < u.name && op.projid == p.projid {
+                    problems.push(format!("users {} and {} have the same \
+                        project id {}", o.name, u.name, p.projid));
+                }
+                if o.name < u.name && op.name == p.name {
+                    problems.push(format!("users {} and {} have the same \
+                        project {}", o.name, u.name, p.name));
+                }
+            }
+        }
+
+        if !u.home.starts_with('/') {
+            problems.push(format!("user {} home {} is not an absolute path",
+                u.name, u.home));
+        }
+
+        if let Some(ds) = &u.home_dataset {
+            if ds.starts_with('/') || ds.ends_with('/') || !ds.contains('/')
+                || ds.contains("//")
+            {
+                problems.push(format!("user {} home_dataset {} is not a \
+                    valid dataset name", u.name, ds));
+            }
+        }
+
+        for (k, v) in u.zfs.iter() {
+            if k != "compression" && parse_size(v).is_none() {
+                problems.push(format!("user {} zfs {} {:?} is not a size",
+                    u.name, k, v));
+            }
+        }
+
+        if !u.shell.starts_with('/') {
+            problems.push(format!("user {} shell {} is not an absolute path",
+                u.name, u.shell));
         }
     }
 
-    Ok(())
+    for g in cfg.groups.values() {
+        for o in cfg.groups.values() {
+            if o.name < g.name && o.gid == g.gid {
+                problems.push(format!("groups {} and {} have the same gid {}",
+                    o.name, g.name, g.gid));
+            }
+        }
+
+        for m in g.members.iter() {
+            if let Some(u) = cfg.users.get(m) {
+                if u.state == State::Absent {
+                    problems.push(format!("group {} member {} is absent",
+                        g.name, m));
+                }
+            }
+        }
+    }
+
+    if let Ok(mut rd) = std::fs::read_dir(files.join("ssh_keys")) {
+        while let Some(ent) = rd.next().transpose()? {
+            let p = ent.path();
+            if p.extension().is_none_or(|e| e != "toml") {
+                continue;
+            }
+
+            let fk: FileKeys = match std::fs::read_to_string(&p)
+                .map_err(|e| anyhow!("{}", e))
+                .and_then(|s| toml::from_str(&s).map_err(|e| anyhow!("{}", e)))
+            {
+                Ok(fk) => fk,
+                Err(e) => {
+                    problems.push(format!("{}: {}", p.display(), e));
+                    continue;
+                }
+            };
+
+            for k in fk.keys.iter() {
+                for e in k.problems() {
+                    problems.push(format!("{}: {}", p.display(), e));
+                }
+            }
+        }
+    }
+
+    if !files.join("dotfiles").is_dir() {
+        problems.push("files/users/dotfiles directory is missing".into());
+    }
+
+    let mut layers = vec![files.join("dotfiles")];
+    if let Ok(mut rd) = std::fs::read_dir(files.join("dotfiles")) {
+        while let Some(ent) = rd.next().transpose()? {
+            if ent.path().is_dir() {
+                layers.push(ent.path());
+            }
+        }
+    }
+    for layer in layers {
+        let p = layer.join("manifest.toml");
+        if !p.is_file() {
+            continue;
+        }
+
+        if let Err(e) = std::fs::read_to_string(&p)
+            .map_err(|e| anyhow!("{}", e))
+            .and_then(|s| toml::from_str::<FileManifest>(&s)
+                .map_err(|e| anyhow!("{}", e)))
+        {
+            problems.push(format!("{}: {}", p.display(), e));
+        }
+    }
+
+    Ok(problems)
+}
+
+/*
+ * List the names of the users and groups in the configuration that are not
+ * absent, for other roles that refer to them.
+ */
+pub fn names(root: &Path) -> Result<(BTreeSet<String>, BTreeSet<String>)> {
+    let cfg = load(read_config(root, "users")?)?;
+
+    Ok((
+        cfg.users.values().filter(|u| u.state != State::Absent)
+            .map(|u| u.name.to_string()).collect(),
+        cfg.groups.values().filter(|g| g.state != State::Absent)
+            .map(|g| g.name.to_string()).collect(),
+    ))
+}
+
+/*
+ * A user that should be able to log in to a particular host, as seen by other
+ * roles that build on the users configuration.
+ */
+pub struct LoginUser {
+    pub name: String,
+    pub class: String,
+    pub groups: Vec<String>,
+}
+
+/*
+ * List the users that have an unlocked account on the host with the specified
+ * node name today, with the primary and supplementary groups of each, in uid
+ * order.
+ */
+pub fn login_users(root: &Path, nodename: &str) -> Result<Vec<LoginUser>> {
+    let cfg = load(read_config(root, "users")?)?;
+    let today = today()?;
+
+    Ok(cfg.users_sorted().into_iter()
+        .filter(|u| u.state == State::Present)
+        .filter(|u| u.applies_to(nodename) && u.active(today))
+        .map(|u| {
+            let mut groups = vec![u.group.to_string()];
+            for g in cfg.groups_sorted() {
+                if (u.groups.contains(&g.name) || g.members.contains(&u.name))
+                    && !groups.contains(&g.name)
+                {
+                    groups.push(g.name.to_string());
+                }
+            }
+
+            LoginUser {
+                name: u.name.to_string(),
+                class: u.class.name().to_string(),
+                groups,
+            }
+        })
+        .collect())
 }
 
 pub fn register(confomat: &mut Confomat) -> Result<()> {
@@ -280,3 +2630,239 @@ pub fn register(confomat: &mut Confomat) -> Result<()> {
         instance_posture: InstancePosture::Prohibited,
     })
 }
+
+/*
+ * A user to be added to users.toml by "confomat users add".
+ */
+pub struct NewUser {
+    pub name: String,
+    pub class: String,
+    pub gecos: Option<String>,
+    pub group: Option<String>,
+    pub key: Option<PathBuf>,
+}
+
+/*
+ * Add a user to users.toml, allocating the lowest uid in the range for their
+ * class that is not used by any existing entry.  The file is edited in place
+ * so that the formatting and comments of the rest of it are preserved, and
+ * the key file, if any, is copied to "ssh_keys/<user>".  Returns the uid.
+ */
+pub fn add(root: &Path, nu: &NewUser) -> Result<i64> {
+    let name = &nu.name;
+    let class = UserClass::from_name(&nu.class)?;
+    if name.is_empty() || name.len() > 32
+        || !name.starts_with(|c: char| c.is_ascii_lowercase())
+        || !name.chars().all(|c| c.is_ascii_lowercase()
+            || c.is_ascii_digit() || c == '_' || c == '-')
+    {
+        bail!("invalid user name {:?}", name);
+    }
+
+    let path = root.join("config").join("users.toml");
+    let toml: ConfomatToml = read_config(root, "users")?;
+    let fc = toml.classes.get(&class);
+    let (lo, hi) = if let Some(r) = fc.and_then(|fc| fc.uid_range) {
+        r
+    } else {
+        bail!("no uid_range configured for class {}", class.name());
+    };
+    let group = if let Some(g) = nu.group.as_ref()
+        .or_else(|| fc.and_then(|fc| fc.group.as_ref()))
+    {
+        g.to_string()
+    } else {
+        bail!("no group specified, and class {} has no default group",
+            class.name());
+    };
+
+    let cfg = load(toml)?;
+    if cfg.users.contains_key(name) {
+        bail!("user {} already exists", name);
+    }
+    if !cfg.groups.contains_key(&group) {
+        bail!("group {} is not in [groups]", group);
+    }
+
+    let uid = if let Some(uid) = (lo..=hi)
+        .find(|uid| cfg.users.values().all(|u| u.uid != *uid))
+    {
+        uid
+    } else {
+        bail!("no free uid in range {}-{} for class {}", lo, hi,
+            class.name());
+    };
+
+    /*
+     * Check the key file before we change anything:
+     */
+    let keyfile = root.join("files").join("users").join("ssh_keys")
+        .join(name);
+    let keys = if let Some(k) = &nu.key {
+        let keys = std::fs::read_to_string(k)
+            .map_err(|e| anyhow!("reading {}: {}", k.display(), e))?;
+        for l in keys.lines().map(str::trim) {
+            if !l.is_empty() && !l.starts_with('#')
+                && l.split_whitespace().count() < 2
+            {
+                bail!("{} does not look like a public key file",
+                    k.display());
+            }
+        }
+        if keyfile.exists() {
+            bail!("{} already exists", keyfile.display());
+        }
+        Some(keys)
+    } else {
+        None
+    };
+
+    let mut doc = std::fs::read_to_string(&path)?
+        .parse::<toml_edit::DocumentMut>()
+        .map_err(|e| anyhow!("parsing {}: {}", path.display(), e))?;
+
+    let mut t = toml_edit::Table::new();
+    t["class"] = toml_edit::value(class.name());
+    if let Some(gecos) = &nu.gecos {
+        t["gecos"] = toml_edit::value(gecos.as_str());
+    }
+    t["uid"] = toml_edit::value(uid);
+    t["group"] = toml_edit::value(group.as_str());
+
+    insert_user(&mut doc, name, t)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    /*
+     * Make sure the result still loads before we replace the file:
+     */
+    let out = doc.to_string();
+    load(toml::from_str(&out)
+        .map_err(|e| anyhow!("parsing new {}: {}", path.display(), e))?)?;
+
+    let tmp = path.with_extension("toml.new");
+    std::fs::write(&tmp, &out)?;
+    std::fs::rename(&tmp, &path)?;
+
+    if let Some(keys) = keys {
+        std::fs::write(&keyfile, keys)?;
+    }
+
+    Ok(uid)
+}
+
+/*
+ * The position of the last table within this one, including tables nested at
+ * any depth, such as the cron jobs or project of a user.
+ */
+fn last_position(t: &toml_edit::Table) -> Option<usize> {
+    t.iter()
+        .filter_map(|(_, v)| match v {
+            toml_edit::Item::Table(t) => {
+                t.position().into_iter().chain(last_position(t)).max()
+            }
+            toml_edit::Item::ArrayOfTables(a) => a.iter()
+                .flat_map(|t| t.position().into_iter().chain(last_position(t)))
+                .max(),
+            _ => None,
+        })
+        .max()
+}
+
+/*
+ * Insert the table for a new user into the document directly after the last
+ * existing user and any of its sub-tables, rather than at the end of the file.
+ */
+fn insert_user(doc: &mut toml_edit::DocumentMut, name: &str,
+    mut t: toml_edit::Table) -> Result<()>
+{
+    let users = doc.entry("users").or_insert_with(|| {
+        let mut t = toml_edit::Table::new();
+        t.set_implicit(true);
+        toml_edit::Item::Table(t)
+    });
+    let users = if let Some(users) = users.as_table_mut() {
+        users
+    } else {
+        bail!("users is not a table");
+    };
+
+    if let Some(pos) = last_position(users) {
+        t.set_position(pos + 1);
+    }
+    users.insert(name, toml_edit::Item::Table(t));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str, from: &[&str]) -> FileKey {
+        FileKey {
+            key: key.to_string(),
+            comment: Some("test".to_string()),
+            from: from.iter().map(|f| f.to_string()).collect(),
+            command: None,
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn ldif_base64() {
+        assert_eq!(ldif_attr("cn", "Jane"), "cn: Jane\n");
+        assert_eq!(ldif_attr("cn", " "), "cn:: IA==\n");
+        assert_eq!(ldif_attr("cn", " a"), "cn:: IGE=\n");
+        assert_eq!(ldif_attr("cn", " ab"), "cn:: IGFi\n");
+        assert_eq!(ldif_attr("cn", "J\u{f6}rg"), "cn:: SsO2cmc=\n");
+        assert_eq!(ldif_attr("cn", ":abcd"), "cn:: OmFiY2Q=\n");
+    }
+
+    #[test]
+    fn key_problems() {
+        assert!(key("ssh-ed25519 AAAAC3Nz+/=", &["10.0.0.0/8"]).problems()
+            .is_empty());
+        assert!(!key("ssh-ed25519 AAAA\nssh-ed25519 BBBB", &[]).problems()
+            .is_empty());
+        assert!(!key("ssh-ed25519 AAAA", &["a\",b"]).problems().is_empty());
+        assert!(!key("AAAA", &[]).problems().is_empty());
+        assert!(!key("ssh-ed25519 AAAA x", &[]).problems().is_empty());
+    }
+
+    fn add_to(doc: &str) -> String {
+        let mut doc = doc.parse::<toml_edit::DocumentMut>().unwrap();
+        let mut t = toml_edit::Table::new();
+        t["uid"] = toml_edit::value(1001);
+        insert_user(&mut doc, "b", t).unwrap();
+        doc.to_string()
+    }
+
+    #[test]
+    fn insert_user_after_last_user() {
+        let out = add_to("\
+            [groups.staff]\ngid = 10\n\n\
+            [users.a]\nuid = 1000\n\n\
+            [classes.ops]\ngroup = \"staff\"\n");
+
+        assert_eq!(out, "\
+            [groups.staff]\ngid = 10\n\n\
+            [users.a]\nuid = 1000\n\n\
+            [users.b]\nuid = 1001\n\n\
+            [classes.ops]\ngroup = \"staff\"\n");
+    }
+
+    #[test]
+    fn insert_user_after_nested_tables() {
+        let out = add_to("\
+            [users.a]\nuid = 1000\n\n\
+            [users.a.cron.x]\nschedule = \"0 * * * *\"\ncommand = \"x\"\n\n\
+            [users.a.project]\nprojid = 100\n\n\
+            [classes.ops]\ngroup = \"staff\"\n");
+
+        let order: Vec<&str> = out.lines()
+            .filter(|l| l.starts_with('['))
+            .collect();
+        assert_eq!(order, vec!["[users.a]", "[users.a.cron.x]",
+            "[users.a.project]", "[users.b]", "[classes.ops]"]);
+    }
+}
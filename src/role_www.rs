@@ -1,10 +1,692 @@
 use super::common::*;
+use super::nginx;
 
-use std::path::PathBuf;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const DEHYDRATED_LIB: &str = "/opt/dehydrated/lib";
+
+const NGINX_ROOT: &str = "/opt/local/etc/nginx";
+const NGINX_STAGING: &str = "/opt/local/etc/nginx.staging";
+
+/*
+ * The parts of the nginx configuration directory that we manage.  Anything
+ * else there, such as "mime.types" from the package, is left alone.
+ */
+const NGINX_MANAGED: &[&str] = &[
+    "nginx.conf",
+    "ssl.conf",
+    "ssl_params.conf",
+    "fullchain.pem",
+    "privkey.pem",
+    "sites",
+    "includes",
+    "certs",
+];
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    dehydrated: Dehydrated,
+    /*
+     * How long to keep trying to obtain the first certificates:
+     */
+    #[serde(default)]
+    bootstrap: Retry,
+}
+
+/*
+ * The release of dehydrated to install.  If no URL is given, the release
+ * archive is downloaded from GitHub.
+ */
+#[derive(Debug, Deserialize)]
+struct Dehydrated {
+    version: String,
+    url: Option<String>,
+    sha256: String,
+}
+
+impl Dehydrated {
+    fn url(&self) -> String {
+        self.url.as_ref().map_or_else(|| format!("https://github.com/\
+            dehydrated-io/dehydrated/releases/download/v{}/{}",
+            self.version, self.tar()), |u| u.to_string())
+    }
+
+    fn tar(&self) -> String {
+        format!("dehydrated-{}.tar.gz", self.version)
+    }
+
+    /*
+     * The directory into which this release is extracted.
+     */
+    fn dir(&self) -> String {
+        format!("{}/dehydrated-{}", DEHYDRATED_LIB, self.version)
+    }
+}
+
+/*
+ * An optional "sites.toml" for an instance describes web sites, from which we
+ * generate nginx server blocks alongside any hand-written site files.
+ */
+#[derive(Debug, Deserialize)]
+struct SitesToml {
+    #[serde(default)]
+    sites: BTreeMap<String, FileSite>,
+}
+
+/*
+ * A site answers to its server names on port 80 and, if "tls" is set, on port
+ * 443 with the named certificate from domains.txt (by default, the first
+ * server name).  Requests over plain HTTP are redirected to HTTPS unless
+ * "redirect_http" is false.  The content is either a redirect of the whole
+ * site to another URL prefix, or any combination of static files under a root
+ * and locations passed to an upstream server.
+ */
+#[derive(Debug, Deserialize)]
+struct FileSite {
+    server_names: Vec<String>,
+    #[serde(default)]
+    tls: bool,
+    certificate: Option<String>,
+    redirect_http: Option<bool>,
+    redirect: Option<String>,
+    root: Option<String>,
+    index: Option<Vec<String>>,
+    #[serde(default)]
+    proxy: Vec<FileProxy>,
+    access_log: Option<String>,
+    error_log: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileProxy {
+    location: String,
+    upstream: String,
+}
+
+/*
+ * Produce a directive, with the value aligned as in our hand-written files.
+ */
+fn directive(indent: usize, key: &str, value: &str) -> String {
+    format!("{:indent$}{:<width$}{};\n", "", key, value, indent = indent,
+        width = 30 - indent)
+}
+
+/*
+ * Values are written into the configuration without quoting, so they must
+ * not contain anything that nginx would treat as syntax.
+ */
+fn is_word(s: &str) -> bool {
+    !s.is_empty() && !s.chars()
+        .any(|c| c.is_whitespace() || "{};\"'#\\".contains(c))
+}
+
+impl FileSite {
+    fn certificate(&self) -> &str {
+        self.certificate.as_deref().unwrap_or_else(|| &self.server_names[0])
+    }
+
+    /*
+     * Check this site against the certificates for the instance, returning a
+     * list of problems.
+     */
+    fn check(&self, name: &str, certs: &[String]) -> Vec<String> {
+        let mut invalid: Vec<(&str, &str)> = Vec::new();
+        let mut bad = |what, v| invalid.push((what, v));
+
+        if !is_word(name) || name.contains('/') {
+            bad("name", name);
+        }
+        if self.server_names.is_empty() {
+            bad("server_names", "");
+        }
+        for n in self.server_names.iter().filter(|n| !is_word(n)) {
+            bad("server name", n);
+        }
+        if self.tls && !self.server_names.is_empty()
+            && !certs.iter().any(|c| c == self.certificate())
+        {
+            bad("certificate (not in domains.txt)", self.certificate());
+        }
+        for (what, v) in &[("root", &self.root), ("access_log",
+            &self.access_log), ("error_log", &self.error_log)]
+        {
+            if let Some(v) = v {
+                if !is_word(v) || !v.starts_with('/') {
+                    bad(what, v);
+                }
+            }
+        }
+        for i in self.index.iter().flatten().filter(|i| !is_word(i)) {
+            bad("index", i);
+        }
+        if let Some(r) = &self.redirect {
+            if !is_word(r) || !r.contains("://") {
+                bad("redirect", r);
+            }
+            if self.root.is_some() || !self.proxy.is_empty() {
+                bad("redirect (with root or proxy)", r);
+            }
+        }
+        for p in self.proxy.iter() {
+            if !is_word(&p.location) || !p.location.starts_with('/')
+                || (p.location == "/" && self.root.is_some())
+            {
+                bad("proxy location", &p.location);
+            }
+            if !is_word(&p.upstream) || !p.upstream.starts_with("http") {
+                bad("proxy upstream", &p.upstream);
+            }
+        }
+
+        let mut problems: Vec<String> = invalid.iter()
+            .map(|(what, v)| format!("site {}: {} {:?} is not valid", name,
+                what, v))
+            .collect();
+        if self.redirect.is_none() && self.root.is_none()
+            && self.proxy.is_empty()
+        {
+            problems.push(format!("site {}: needs a redirect, root or proxy",
+                name));
+        }
+
+        problems
+    }
+
+    fn content(&self) -> String {
+        let mut out = String::new();
+
+        if let Some(r) = &self.redirect {
+            out += "\n  location / {\n";
+            out += &directive(4, "return", &format!("301 {}$request_uri", r));
+            out += "  }\n";
+        }
+        if let Some(root) = &self.root {
+            out += "\n  location / {\n";
+            out += &directive(4, "root", root);
+            out += &directive(4, "index", &self.index.as_ref()
+                .map_or_else(|| "index.html index.htm".to_string(),
+                    |i| i.join(" ")));
+            out += "  }\n";
+        }
+        for p in self.proxy.iter() {
+            out += &format!("\n  location {} {{\n", p.location);
+            out += &directive(4, "proxy_pass", &p.upstream);
+            out += &directive(4, "proxy_set_header", "Host $host");
+            out += &directive(4, "proxy_set_header",
+                "X-Forwarded-For $proxy_add_x_forwarded_for");
+            out += &directive(4, "proxy_set_header",
+                "X-Forwarded-Proto $scheme");
+            out += "  }\n";
+        }
+
+        out
+    }
+
+    /*
+     * Render the server blocks for this site.
+     */
+    fn render(&self, name: &str) -> String {
+        let names = self.server_names.join(" ");
+        let mut logs = String::new();
+        if let Some(l) = &self.error_log {
+            logs += &directive(2, "error_log", l);
+        }
+        if let Some(l) = &self.access_log {
+            logs += &directive(2, "access_log", &format!("{} local", l));
+        }
+
+        let mut out = format!("#\n# {}: generated from sites.toml; do not \
+            edit\n#\n\nserver {{\n", name);
+        out += &directive(2, "listen", "80");
+        out += &directive(2, "server_name", &names);
+        out += &logs;
+        out += "\n  location /.well-known/acme-challenge {\n";
+        out += &directive(4, "alias", "/var/www/challenges");
+        out += "  }\n";
+        if self.tls && self.redirect_http.unwrap_or(true) {
+            out += "\n  location / {\n";
+            out += &directive(4, "return", "301 https://$host$request_uri");
+            out += "  }\n";
+        } else {
+            out += &self.content();
+        }
+        out += "}\n";
+
+        if self.tls {
+            out += "\nserver {\n";
+            out += &directive(2, "listen", "443 ssl http2");
+            out += &directive(2, "server_name", &names);
+            out += &directive(2, "include",
+                &format!("certs/{}.conf", self.certificate()));
+            out += &directive(2, "include", "ssl_params.conf");
+            out += &logs;
+            out += &self.content();
+            out += "}\n";
+        }
+
+        out
+    }
+
+    /*
+     * The directories that must exist for the log files of this site.
+     */
+    fn logdirs(&self) -> Vec<PathBuf> {
+        self.access_log.iter().chain(self.error_log.iter())
+            .filter_map(|l| Path::new(l).parent().map(|p| p.to_path_buf()))
+            .collect()
+    }
+}
+
+/*
+ * Check the dehydrated settings, returning a list of problems.
+ */
+fn check_dehydrated(d: &Dehydrated) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if d.version.is_empty() || !d.version.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    {
+        problems.push(format!("dehydrated version {:?} is not valid",
+            d.version));
+    }
+    if d.sha256.len() != 64 || !d.sha256.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        problems.push(format!("dehydrated sha256 {:?} is not a SHA-256 \
+            digest", d.sha256));
+    }
+    if !d.url().starts_with("https://") {
+        problems.push(format!("dehydrated url {} is not https", d.url()));
+    }
+
+    problems
+}
+
+/*
+ * Check the bootstrap retry policy, returning a list of problems.
+ */
+fn check_retry(r: &Retry) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if r.attempts == 0 {
+        problems.push("bootstrap attempts must be at least 1".into());
+    }
+    if r.initial_delay == 0 || r.max_delay < r.initial_delay {
+        problems.push("bootstrap delays must be non-zero, with max_delay at \
+            least initial_delay".into());
+    }
+
+    problems
+}
+
+/*
+ * Read the installed nginx configuration, following includes, and make sure
+ * the paths it refers to are in place: log directories are created, and
+ * certificate files must exist, as nginx will not start without them.  A
+ * missing document root is only reported, as it may be populated later by
+ * some other means.
+ */
+fn prepare_nginx(c: &Context, main: &Path) -> Result<()> {
+    let log = c.log();
+
+    let refs = nginx::references(main)?;
+
+    for ld in refs.logdirs.iter() {
+        debug!(log, "configuration uses log directory {}", ld.display());
+        c.ensure_dir(ld, ROOT, ROOT, 0o750)?;
+    }
+
+    for dr in refs.docroots.iter() {
+        if !dr.is_dir() {
+            warn!(log, "document root {} does not exist", dr.display());
+        }
+    }
+
+    let missing: Vec<String> = refs.files.iter()
+        .filter(|(f, _)| !f.exists())
+        .map(|(f, at)| format!("{} ({})", f.display(), at))
+        .collect();
+    if !missing.is_empty() {
+        bail!("nginx configuration refers to missing files: {}",
+            missing.join(", "));
+    }
+
+    Ok(())
+}
+
+/*
+ * The complete nginx configuration for an instance, which is assembled in a
+ * staging directory and checked there before it is installed.
+ */
+struct StagedConfig<'a> {
+    names: &'a [String],
+    generated: &'a [(String, String)],
+}
+
+impl StagedConfig<'_> {
+    /*
+     * Bring the staging directory up to date with the configuration, with
+     * temporary self-signed certificates for the names listed in "bootstrap",
+     * and check that nginx will accept it.  The staging directory is kept
+     * between runs, so that only files which have changed are rewritten and
+     * bootstrap certificates are not generated again.  The live configuration
+     * is not modified.
+     */
+    fn stage(&self, c: &Context, bootstrap: &[String]) -> Result<PathBuf> {
+        let log = c.log();
+        let staging = PathBuf::from(NGINX_STAGING);
+
+        info!(log, "staging nginx configuration in {}", NGINX_STAGING);
+        c.ensure_dir(&staging, ROOT, ROOT, 0o700)?;
+
+        /*
+         * Copy the base configuration files:
+         */
+        for n in &["nginx.conf", "ssl.conf", "ssl_params.conf"] {
+            c.ensure_file(c.file(n)?, staging.join(n),
+                ROOT, ROOT, 0o600, Create::Always)?;
+        }
+
+        for dir in &["sites", "includes"] {
+            let path = staging.join(dir);
+
+            c.ensure_dir(&path, ROOT, ROOT, 0o700)?;
+
+            let mut names: Vec<String> = Vec::new();
+            if let Some(files) = c.files_maybe(dir)? {
+                for f in &files {
+                    let n = f.file_name().unwrap();
+                    names.push(n.to_string_lossy().to_string());
+
+                    c.ensure_file(f, path.join(n), ROOT, ROOT, 0o600,
+                        Create::Always)?;
+                }
+            }
+
+            if *dir == "sites" {
+                for (n, contents) in self.generated.iter() {
+                    if names.contains(n) {
+                        bail!("generated site file {} would replace a file \
+                            of the same name", n);
+                    }
+                    names.push(n.to_string());
+
+                    ensure_contents(c, contents, path.join(n), ROOT, ROOT,
+                        0o600)?;
+                }
+            }
+
+            prune(c, &path, &names)?;
+        }
+
+        /*
+         * Each certificate has a directory under "certs" with links to the
+         * files that dehydrated maintains, and a snippet that site files can
+         * include to use that certificate:
+         */
+        let certroot = staging.join("certs");
+        c.ensure_dir(&certroot, ROOT, ROOT, 0o700)?;
+
+        let mut names: Vec<String> = Vec::new();
+        for name in self.names.iter() {
+            let dir = certroot.join(name);
+            c.ensure_dir(&dir, ROOT, ROOT, 0o700)?;
+            names.push(name.to_string());
+            names.push(format!("{}.conf", name));
+
+            ensure_contents(c, &format!("ssl_certificate             \
+                certs/{}/fullchain.pem;\n\
+                ssl_certificate_key         certs/{}/privkey.pem;\n", name,
+                name), certroot.join(format!("{}.conf", name)), ROOT, ROOT,
+                0o600)?;
+
+            let files = ["fullchain.pem", "privkey.pem"].iter()
+                .map(|n| dir.join(n))
+                .collect::<Vec<_>>();
+            let regular = |p: &PathBuf| {
+                p.symlink_metadata().map(|m| m.is_file()).unwrap_or(false)
+            };
+
+            if !bootstrap.contains(name) {
+                /*
+                 * Remove any bootstrap certificate left from an earlier run
+                 * before linking to the real one.
+                 */
+                for f in files.iter().filter(|f| regular(f)) {
+                    c.ensure_removed(f)?;
+                }
+                link_cert(c, &certroot, name)?;
+                continue;
+            }
+
+            if files.iter().all(regular) {
+                /*
+                 * We already made a bootstrap certificate for this name.
+                 */
+                continue;
+            }
+
+            /*
+             * Make sure we do not write through a link to the files that
+             * dehydrated maintains.
+             */
+            for f in files.iter() {
+                c.ensure_removed(f)?;
+            }
+
+            info!(log, "create temporary bootstrap certificate for {}...",
+                name);
+            c.run(&["/opt/local/bin/openssl", "req",
+                "-x509",
+                "-newkey", "rsa:4096",
+                "-keyout", files[1].to_str().expect("privkey"),
+                "-out", files[0].to_str().expect("fullchain"),
+                "-days", "2",
+                "-nodes",
+                "-sha256",
+                "-subj", &format!("/CN={}", name)])?;
+        }
+
+        prune(c, &certroot, &names)?;
+
+        /*
+         * The links in the base directory used by "ssl.conf" refer to the
+         * certificate for the primary name.  They are relative, so that they
+         * work both here and once installed.
+         */
+        for n in &["fullchain.pem", "privkey.pem"] {
+            c.ensure_symlink(staging.join(n),
+                format!("certs/{}/{}", self.names[0], n), ROOT, ROOT)?;
+        }
+
+        let conf = staging.join("nginx.conf");
+        prepare_nginx(c, &conf)?;
+
+        info!(log, "checking staged nginx configuration...");
+        if let Err(e) = run_capture(c, &["/opt/local/sbin/nginx", "-t", "-c",
+            conf.to_str().unwrap()])
+        {
+            bail!("staged nginx configuration in {} is not valid, so the live \
+                configuration was left alone: {}", NGINX_STAGING, e);
+        }
+
+        Ok(staging)
+    }
+}
+
+fn list_dir(p: &Path) -> Result<Vec<String>> {
+    let mut out = Vec::new();
+    let mut rd = std::fs::read_dir(p)?;
+    while let Some(ent) = rd.next().transpose()? {
+        out.push(ent.file_name().to_string_lossy().to_string());
+    }
+    out.sort();
+    Ok(out)
+}
+
+/*
+ * Remove a file, link or directory tree if it exists.
+ */
+fn remove_path(c: &Context, p: &Path) -> Result<bool> {
+    match p.symlink_metadata() {
+        Ok(m) if m.is_dir() => {
+            c.run(&["/usr/bin/rm", "-rf", &p.to_string_lossy()])?;
+            Ok(true)
+        }
+        Ok(_) => c.ensure_removed(p),
+        Err(_) => Ok(false),
+    }
+}
+
+/*
+ * Remove any entries in a directory other than those we expect to find.
+ */
+fn prune(c: &Context, dir: &Path, keep: &[String]) -> Result<bool> {
+    let mut changed = false;
+
+    for n in list_dir(dir)? {
+        if !keep.contains(&n) {
+            info!(c.log(), "removing {}", dir.join(&n).display());
+            changed |= remove_path(c, &dir.join(&n))?;
+        }
+    }
+
+    Ok(changed)
+}
+
+/*
+ * Make a file, link or directory tree in the live configuration match the
+ * staged copy, returning true if anything was changed.  An entry that is of a
+ * different kind in the two trees is removed and then replaced.
+ */
+fn sync_tree(c: &Context, staged: &Path, live: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let kind = |p: &Path| {
+        p.symlink_metadata().ok()
+            .map(|m| (m.file_type().is_symlink(), m.is_dir()))
+    };
+
+    let mut changed = false;
+    let ks = kind(staged);
+    if kind(live).is_some() && kind(live) != ks {
+        changed |= remove_path(c, live)?;
+    }
+
+    let m = match (ks, staged.symlink_metadata()) {
+        (Some(_), Ok(m)) => m,
+        _ => return Ok(changed),
+    };
+    let mode = m.mode() & 0o7777;
+
+    if m.file_type().is_symlink() {
+        changed |= c.ensure_symlink(live, std::fs::read_link(staged)?,
+            ROOT, ROOT)?;
+    } else if !m.is_dir() {
+        changed |= c.ensure_file(staged, live, ROOT, ROOT, mode,
+            Create::Always)?;
+    } else {
+        changed |= c.ensure_dir(live, ROOT, ROOT, mode)?;
+
+        let names = list_dir(staged)?;
+        changed |= prune(c, live, &names)?;
+        for n in names.iter() {
+            changed |= sync_tree(c, &staged.join(n), &live.join(n))?;
+        }
+    }
+
+    Ok(changed)
+}
+
+/*
+ * Copy each part of a checked staging directory into the live configuration,
+ * and if anything changed, ask nginx to load the new configuration.
+ */
+fn install_nginx(c: &Context, staging: &Path) -> Result<()> {
+    let log = c.log();
+    let live = Path::new(NGINX_ROOT);
+
+    let mut changed = false;
+    for n in NGINX_MANAGED.iter() {
+        if sync_tree(c, &staging.join(n), &live.join(n))? {
+            info!(log, "installed nginx configuration: {}", n);
+            changed = true;
+        }
+    }
+
+    if changed {
+        info!(log, "refreshing nginx...");
+        c.run(&["/usr/sbin/svcadm", "refresh", "pkgsrc/nginx"])?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Link the certificate files that dehydrated maintains for this name into the
+ * nginx configuration directory.
+ */
+fn link_cert(c: &Context, certroot: &Path, name: &str) -> Result<()> {
+    for n in &["fullchain.pem", "privkey.pem"] {
+        c.ensure_symlink(certroot.join(name).join(n),
+            format!("/var/opt/dehydrated/certs/{}/{}", name, n), ROOT, ROOT)?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Determine the certificate names from the lines of "domains.txt".  As with
+ * dehydrated, each line describes one certificate, named for the first domain
+ * on the line unless an alias is given after a ">".
+ */
+fn cert_names(lines: &[String]) -> Result<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+
+    for l in lines {
+        let l = l.split('#').next().unwrap().trim();
+        if l.is_empty() {
+            continue;
+        }
+
+        let mut parts = l.splitn(2, '>');
+        let first = match parts.next().unwrap().split_whitespace().next() {
+            Some(d) => d,
+            None => bail!("domains.txt line {:?} has no domains", l),
+        };
+        let name = match parts.next().map(str::trim) {
+            Some(alias) if !alias.is_empty() => alias,
+            Some(_) => bail!("domains.txt line {:?} has an empty alias", l),
+            None => first,
+        };
+
+        if name.contains('/') || name.starts_with('.') {
+            bail!("domains.txt certificate name {:?} is not valid", name);
+        }
+        if names.iter().any(|n| n == name) {
+            bail!("domains.txt lists certificate {} more than once", name);
+        }
+        names.push(name.to_string());
+    }
+
+    if names.is_empty() {
+        bail!("domains.txt was empty");
+    }
+
+    Ok(names)
+}
 
 fn role_www(c: &Context) -> Result<()> {
     let log = c.log();
 
+    let cfg: Config = c.config()?;
+    let mut problems = check_dehydrated(&cfg.dehydrated);
+    problems.extend(check_retry(&cfg.bootstrap));
+    if !problems.is_empty() {
+        bail!("www configuration: {}", problems.join("; "));
+    }
+
     c.ensure_packages(&["nginx", "gtar"])?;
 
     info!(log, "creating base dehydrated directories");
@@ -26,30 +708,68 @@ fn role_www(c: &Context) -> Result<()> {
     c.ensure_symlink("/opt/dehydrated/workaround/grep", "/usr/bin/ggrep",
         ROOT, ROOT)?;
 
-    let ver = "0.6.5";
-    let base = "https://github.com/lukas2511/dehydrated/releases/download";
-    let tar = format!("dehydrated-{}.tar.gz", ver);
-    let url = format!("{}/v{}/{}", base, ver, tar);
-    let md5 = "cedf07369517c317c4e1075540b94699";
-
+    let d = &cfg.dehydrated;
+    let tar = d.tar();
     let tf = format!("/var/tmp/{}", tar);
+    let dir = d.dir();
+    let prog = format!("{}/dehydrated", dir);
+
+    let installed = match c.check(&prog)? {
+        Some(fi) => fi.is_user_executable(),
+        None => false,
+    };
+    if !installed {
+        c.ensure_download(&d.url(), &tf, &d.sha256, HashType::SHA256)?;
 
-    if !c.exists_file("/opt/dehydrated/lib/dehydrated-0.6.5/README.md")? {
-        c.ensure_download(&url, &tf, &md5, HashType::MD5)?;
+        /*
+         * Discard anything left over from an extraction that did not finish
+         * before we try again:
+         */
+        c.run(&["/usr/bin/rm", "-rf", &dir])?;
 
         info!(log, "extracting archive: {}", tf);
         c.run(&["/opt/local/bin/gtar", "-xz",
-            "-C", "/opt/dehydrated/lib",
+            "-C", DEHYDRATED_LIB,
             "-f", &tf])?;
+
+        if !c.exists_file(&prog)? {
+            bail!("archive {} did not contain {}", tf, prog);
+        }
     }
 
     /*
      * Install a wrapper which will first chdir(2) to the work directory in
      * "/var/opt/dehydrated" before running the real thing.
      */
-    c.ensure_file(c.file("scripts/dehydrated.sh")?,
-        "/opt/dehydrated/bin/dehydrated", ROOT, ROOT, 0o755,
-        Create::Always)?;
+    let wrapper = format!("#!/bin/bash\n\
+        \n\
+        if ! cd /var/opt/dehydrated; then\n\
+        \texit 1\n\
+        fi\n\
+        \n\
+        export PATH=\"/opt/dehydrated/workaround:$PATH\"\n\
+        \n\
+        exec '{}' \"$@\"\n", prog);
+    ensure_contents(c, &wrapper, "/opt/dehydrated/bin/dehydrated", ROOT,
+        ROOT, 0o755)?;
+
+    /*
+     * Now that the wrapper refers to the configured release, remove any other
+     * release we installed in the past, along with its archive:
+     */
+    let mut rd = std::fs::read_dir(DEHYDRATED_LIB)?;
+    while let Some(ent) = rd.next().transpose()? {
+        let path = ent.path();
+        let n = ent.file_name().to_string_lossy().to_string();
+
+        if n.starts_with("dehydrated-") && path != Path::new(&dir) {
+            info!(log, "removing old release {}", path.display());
+            c.run(&["/usr/bin/rm", "-rf", &path.to_string_lossy()])?;
+
+            let otf = format!("/var/tmp/{}.tar.gz", n);
+            c.ensure_removed(&otf)?;
+        }
+    }
 
     /*
      * Install the reconfiguration hook:
@@ -83,190 +803,216 @@ fn role_www(c: &Context) -> Result<()> {
     c.ensure_dir("/var/www/htdocs", ROOT, "www", 0o750)?;
     c.ensure_dir("/var/www/challenges", ROOT, "www", 0o750)?;
 
-    let cfgroot = PathBuf::from("/opt/local/etc/nginx");
-    let cfgfile = |n: &str| -> PathBuf {
-        let mut r = cfgroot.clone();
-        r.push(n);
-        r
-    };
-
     /*
-     * Copy the base configuration files:
+     * Determine the name of each certificate for this instance.  The first
+     * is the primary name, used for the certificate in "ssl.conf".
      */
-    for n in &["nginx.conf", "ssl.conf"] {
-        c.ensure_file(c.file(n)?, cfgfile(n),
-            ROOT, ROOT, 0o600, Create::Always)?;
-    }
+    let names = match c.read_lines(c.file("domains.txt")?)? {
+        None => bail!("domains.txt missing for instance"),
+        Some(l) => cert_names(&l)?,
+    };
 
     /*
      * Build a list of access or error log target directories to create:
      */
     let mut logdirs: Vec<PathBuf> = Vec::new();
 
-    for dir in &["sites", "includes"] {
-        let path = cfgfile(dir);
-
-        c.ensure_dir(&path, ROOT, ROOT, 0o700)?;
-
-        /*
-         * Ensure that all of the files we have are installed.
-         */
-        let names = if let Some(files) = c.files_maybe(dir)? {
-            let mut names: Vec<std::ffi::OsString> = Vec::new();
-            for f in &files {
-                let n = f.file_name().unwrap();
-                names.push(n.to_os_string());
-
-                let mut p = path.clone();
-                p.push(n);
-
-                c.ensure_file(f, p, ROOT, ROOT, 0o600, Create::Always)?;
+    /*
+     * Render any sites described in "sites.toml".  We know where the logs
+     * for these sites go, so their directories need not be found by scanning.
+     */
+    let mut generated: Vec<(String, String)> = Vec::new();
+    if let Some(f) = c.file_maybe("sites.toml")? {
+        let st: SitesToml = toml::from_str(&std::fs::read_to_string(&f)?)
+            .map_err(|e| anyhow!("parsing {}: {}", f.display(), e))?;
 
-                /*
-                 * Scan this file for log file paths:
-                 */
-                debug!(log, "scanning {} for log configuration", f.display());
-                let lines = c.read_lines(f)?.expect("should still exist");
-                for l in lines {
-                    let t: Vec<_> = l.trim().split_whitespace().collect();
-                    if t.len() >= 2 && t[0] == "access_log" && t[1] != "off" {
-                        /*
-                         * The access_log directive accepts a log file path, and
-                         * we need to ensure that the containing directory
-                         * exists:
-                         */
-                        let mut dir = PathBuf::from(t[1]);
-                        assert!(dir.pop());
-
-                        if !logdirs.contains(&dir) {
-                            info!(log, "file {} configures log directory {}",
-                                f.display(), dir.display());
-                            logdirs.push(dir);
-                        }
-                    }
-                }
+        for (name, site) in st.sites.iter() {
+            let problems = site.check(name, &names);
+            if !problems.is_empty() {
+                bail!("sites.toml: {}", problems.join("; "));
             }
-            names
-        } else {
-            Vec::new()
-        };
 
-        /*
-         * Finally, remove any files that are no longer present:
-         */
-        let mut rd = std::fs::read_dir(&path)?;
-        while let Some(ent) = rd.next().transpose()? {
-            let path = ent.path();
-            let n = path.file_name().unwrap();
-
-            if names.iter().any(|nn| nn == n) {
-                continue;
+            info!(log, "generating site {}", name);
+            generated.push((format!("{}.conf", name), site.render(name)));
+            for dir in site.logdirs() {
+                if !logdirs.contains(&dir) {
+                    logdirs.push(dir);
+                }
             }
-
-            info!(log, "removing old site file \"{}\"", path.display());
-            c.ensure_removed(&path)?;
         }
     }
 
     /*
-     * Create any log directories we found in the configuration files:
+     * Create the log directories for the generated sites:
      */
     for ld in logdirs {
         c.ensure_dir(&ld, ROOT, ROOT, 0o750)?;
     }
 
     /*
-     * Determine the primary domain name for this instance.
+     * If dehydrated does not yet have a certificate for some name, we will
+     * need to bootstrap:
      */
-    let sname = match c.read_lines(c.file("domains.txt")?)? {
-        None => bail!("domains.txt missing for instance"),
-        Some(l) => {
-            if let Some(x) = l.first() {
-                x.trim().split_whitespace().next().unwrap().to_string()
-            } else {
-                bail!("domains.txt was empty");
-            }
+    let mut bootstrap = Vec::new();
+    for name in names.iter() {
+        let fullchain = format!("/var/opt/dehydrated/certs/{}/fullchain.pem",
+            name);
+        if !c.exists_file(&fullchain)? {
+            bootstrap.push(name.to_string());
         }
+    }
+
+    let sc = StagedConfig {
+        names: &names,
+        generated: &generated,
     };
 
-    /*
-     * Determine whether we need to bootstrap or not:
-     */
-    let fullchain = format!("/var/opt/dehydrated/certs/{}/fullchain.pem",
-        sname);
-    let privkey = format!("/var/opt/dehydrated/certs/{}/privkey.pem",
-        sname);
-    let bootstrap = !c.exists_file(&fullchain)?;
+    if !bootstrap.is_empty() {
+        /*
+         * Bootstrap:
+         */
+        let staging = sc.stage(c, &bootstrap)?;
+        install_nginx(c, &staging)?;
 
-    let link_fullchain = cfgfile("fullchain.pem");
-    let link_privkey = cfgfile("privkey.pem");
+        info!(log, "enabling nginx...");
+        c.ensure_online("pkgsrc/nginx", true)?;
 
-    if bootstrap {
         /*
-         * Bootstrap:
+         * Mistakes in domains.txt or rate limits at the CA will not resolve
+         * themselves, so we only retry for a while before giving up and
+         * reporting what dehydrated had to say.
          */
-        info!(log, "create temporary bootstrap certificates...");
+        info!(log, "bootstrap lets encrypt...");
+        retry(log, "dehydrated bootstrap", &cfg.bootstrap, || {
+            run_capture(c, &["/opt/dehydrated/bin/dehydrated", "--accept-terms",
+                "--register"])
+                .map_err(|e| anyhow!("registration: {}", e))?;
+            run_capture(c, &["/opt/dehydrated/bin/dehydrated", "--cron"])
+                .map_err(|e| anyhow!("obtaining certificates: {}", e))?;
+            Ok(())
+        })?;
+        info!(log, "certificates obtained!");
+    }
+
+    /*
+     * Install the configuration with links to the real certificates, which
+     * replaces any bootstrap certificates:
+     */
+    let staging = sc.stage(c, &[])?;
+    install_nginx(c, &staging)?;
 
-        c.ensure_removed(&link_fullchain)?;
-        c.ensure_removed(&link_privkey)?;
+    info!(log, "enabling nginx...");
+    c.ensure_online("pkgsrc/nginx", true)?;
 
-        c.run(&["/opt/local/bin/openssl", "req",
-            "-x509",
-            "-newkey", "rsa:4096",
-            "-keyout", link_privkey.to_str().expect("privkey"),
-            "-out", link_fullchain.to_str().expect("fullchain"),
-            "-days", "2",
-            "-nodes",
-            "-sha256",
-            "-subj", &format!("/CN={}", sname)])?;
+    info!(log, "configuring TLS renewal cron job...");
+    c.ensure_cron(ROOT, "dehydrated",
+        "0 0 * * * /opt/dehydrated/bin/dehydrated --cron")?;
 
-        info!(log, "checking nginx configuration...");
-        c.run(&["/opt/local/sbin/nginx", "-t"])?;
+    Ok(())
+}
 
-        info!(log, "enabling nginx...");
-        c.ensure_online("pkgsrc/nginx", true)?;
+/*
+ * Validate the files for each web server instance without a live system.
+ */
+pub fn check(root: &Path) -> Result<Vec<String>> {
+    let cfg: Config = read_config(root, "www")?;
+    let files = root.join("files").join("www");
+    let mut problems = check_dehydrated(&cfg.dehydrated);
+    problems.extend(check_retry(&cfg.bootstrap));
 
-        info!(log, "bootstrap lets encrypt...");
-        loop {
-            if let Err(e) = c.run(&["/opt/dehydrated/bin/dehydrated",
-                "--accept-terms", "--register"])
-            {
-                warn!(log, "failed to register (retry): {}", e);
-                sleep(5);
-                continue;
-            }
+    /*
+     * Files are found first in the instance directory, and then in the
+     * directory for the role:
+     */
+    let find = |inst: &Path, n: &str| -> Option<PathBuf> {
+        [inst.join(n), files.join(n)].iter().find(|p| p.exists()).cloned()
+    };
 
-            if let Err(e) = c.run(&["/opt/dehydrated/bin/dehydrated",
-                "--cron"])
-            {
-                warn!(log, "failed to get certificates (retry): {}", e);
-                sleep(5);
-                continue;
+    let mut rd = std::fs::read_dir(files.join("instances"))?;
+    while let Some(ent) = rd.next().transpose()? {
+        let inst = ent.path();
+        let name = ent.file_name().to_string_lossy().to_string();
+
+        for n in &["nginx.conf", "ssl.conf", "ssl_params.conf", "config",
+            "scripts/hook.sh"]
+        {
+            if find(&inst, n).is_none() {
+                problems.push(format!("instance {}: file {} is missing",
+                    name, n));
             }
+        }
 
-            info!(log, "certificates obtained!");
-            break;
+        /*
+         * Make sure every nginx configuration file at least parses:
+         */
+        let mut confs: Vec<PathBuf> = ["nginx.conf", "ssl.conf",
+            "ssl_params.conf"].iter().filter_map(|n| find(&inst, n)).collect();
+        for dir in &["sites", "includes"] {
+            if let Ok(mut rd) = std::fs::read_dir(inst.join(dir)) {
+                while let Some(ent) = rd.next().transpose()? {
+                    confs.push(ent.path());
+                }
+            }
+        }
+        for f in confs.iter() {
+            if let Err(e) = nginx::parse_file(f) {
+                problems.push(format!("instance {}: {}", name, e));
+            }
         }
 
-        info!(log, "removing bootstrap certificates...");
-        c.ensure_removed(&link_fullchain)?;
-        c.ensure_removed(&link_privkey)?;
+        match find(&inst, "domains.txt") {
+            Some(p) => {
+                let l: Vec<String> = std::fs::read_to_string(&p)?.lines()
+                    .map(str::to_string).collect();
+                match cert_names(&l) {
+                    Ok(certs) => problems.extend(check_sites(&inst,
+                        find(&inst, "sites.toml"), &certs)
+                        .into_iter()
+                        .map(|p| format!("instance {}: {}", name, p))),
+                    Err(e) => problems.push(format!("instance {}: {}", name,
+                        e)),
+                }
+            }
+            None => problems.push(format!("instance {}: domains.txt is \
+                missing", name)),
+        }
     }
 
-    c.ensure_symlink(&link_fullchain, fullchain, ROOT, ROOT)?;
-    c.ensure_symlink(&link_privkey, privkey, ROOT, ROOT)?;
+    Ok(problems)
+}
 
-    info!(log, "checking nginx configuration...");
-    c.run(&["/opt/local/sbin/nginx", "-t"])?;
+/*
+ * Check the sites described by the "sites.toml" for an instance, if it has
+ * one, returning a list of problems.
+ */
+fn check_sites(inst: &Path, sites: Option<PathBuf>, certs: &[String])
+    -> Vec<String>
+{
+    let p = if let Some(p) = sites {
+        p
+    } else {
+        return Vec::new();
+    };
 
-    info!(log, "enabling nginx...");
-    c.ensure_online("pkgsrc/nginx", true)?;
+    let st: SitesToml = match std::fs::read_to_string(&p)
+        .map_err(|e| anyhow!("{}", e))
+        .and_then(|s| toml::from_str(&s).map_err(|e| anyhow!("{}", e)))
+    {
+        Ok(st) => st,
+        Err(e) => return vec![format!("{}: {}", p.display(), e)],
+    };
 
-    info!(log, "configuring TLS renewal cron job...");
-    c.ensure_cron(ROOT, "dehydrated",
-        "0 0 * * * /opt/dehydrated/bin/dehydrated --cron")?;
+    let mut problems = Vec::new();
+    for (name, site) in st.sites.iter() {
+        problems.extend(site.check(name, certs));
 
-    Ok(())
+        if inst.join("sites").join(format!("{}.conf", name)).exists() {
+            problems.push(format!("site {}: sites/{}.conf already exists",
+                name, name));
+        }
+    }
+
+    problems
 }
 
 pub fn register(confomat: &mut Confomat) -> Result<()> {
@@ -276,3 +1022,117 @@ pub fn register(confomat: &mut Confomat) -> Result<()> {
         instance_posture: InstancePosture::Required,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(s: &[&str]) -> Vec<String> {
+        s.iter().map(|l| l.to_string()).collect()
+    }
+
+    fn site(s: &str) -> FileSite {
+        toml::from_str(s).unwrap()
+    }
+
+    const APP: &str = r#"
+        server_names = [ "app.example.com", "app.test" ]
+        tls = true
+        certificate = "app"
+        root = "/var/www/app"
+        access_log = "/var/log/app/access.log"
+
+        [[proxy]]
+        location = "/api/"
+        upstream = "http://127.0.0.1:8080"
+    "#;
+
+    #[test]
+    fn render_tls_site_with_proxy() {
+        let expected = [
+            "#",
+            "# app: generated from sites.toml; do not edit",
+            "#",
+            "",
+            "server {",
+            "  listen                      80;",
+            "  server_name                 app.example.com app.test;",
+            "  access_log                  /var/log/app/access.log local;",
+            "",
+            "  location /.well-known/acme-challenge {",
+            "    alias                     /var/www/challenges;",
+            "  }",
+            "",
+            "  location / {",
+            "    return                    301 https://$host$request_uri;",
+            "  }",
+            "}",
+            "",
+            "server {",
+            "  listen                      443 ssl http2;",
+            "  server_name                 app.example.com app.test;",
+            "  include                     certs/app.conf;",
+            "  include                     ssl_params.conf;",
+            "  access_log                  /var/log/app/access.log local;",
+            "",
+            "  location / {",
+            "    root                      /var/www/app;",
+            "    index                     index.html index.htm;",
+            "  }",
+            "",
+            "  location /api/ {",
+            "    proxy_pass                http://127.0.0.1:8080;",
+            "    proxy_set_header          Host $host;",
+            "    proxy_set_header          X-Forwarded-For \
+                $proxy_add_x_forwarded_for;",
+            "    proxy_set_header          X-Forwarded-Proto $scheme;",
+            "  }",
+            "}",
+        ];
+
+        let out = site(APP).render("app");
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn site_problems() {
+        let certs = lines(&["app"]);
+        assert!(site(APP).check("app", &certs).is_empty());
+        assert_eq!(site(APP).check("app", &[]).len(), 1);
+
+        let bad = site(r#"
+            server_names = [ "a b" ]
+            root = "var/www"
+            redirect = "https://example.com"
+        "#);
+        assert_eq!(bad.check("bad", &certs).len(), 3);
+
+        let empty = site(r#"server_names = [ "x.example.com" ]"#);
+        assert_eq!(empty.check("empty", &certs), vec!["site empty: needs a \
+            redirect, root or proxy".to_string()]);
+    }
+
+    #[test]
+    fn cert_names_aliases_and_comments() {
+        let names = cert_names(&lines(&[
+            "# certificates for the example instance",
+            "example.com www.example.com",
+            "",
+            "  app.example.com api.example.com > app  # two names",
+            "mail.example.com>mail",
+            "static.example.com # trailing comment",
+        ])).unwrap();
+        assert_eq!(names, vec!["example.com", "app", "mail",
+            "static.example.com"]);
+    }
+
+    #[test]
+    fn cert_names_problems() {
+        assert!(cert_names(&lines(&["# nothing", ""])).is_err());
+        assert!(cert_names(&lines(&["a.example.com >"])).is_err());
+        assert!(cert_names(&lines(&["> alias"])).is_err());
+        assert!(cert_names(&lines(&["a.example.com > ../x"])).is_err());
+        assert!(cert_names(&lines(&["a.example.com", "b.example.com > \
+            a.example.com"])).is_err());
+    }
+}